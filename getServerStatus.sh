@@ -3,8 +3,7 @@
 # SERVER="ws://tarot.rhumbs.fr/ws"
 SERVER="ws://127.0.0.1:8001/ws"
 
-UUID="25d0ca15-225e-40e5-9485-789d0b1529e0"
-GUID="none"
-# UUID="toto"
+# Token given to the server with --admin-token
+ADMIN_TOKEN="${ADMIN_TOKEN:?set ADMIN_TOKEN to the admin token of the server}"
 
-(echo '{"cmd": "show_server_status"}';sleep 0.1) | websocat $SERVER/$GUID"_"$UUID | jq
+(echo '{"cmd": "admin_login", "token": "'"$ADMIN_TOKEN"'"}';echo '{"cmd": "show_server_status"}';sleep 0.1) | websocat $SERVER/none | jq
@@ -1,7 +1,8 @@
 use chrono::{Utc, DateTime};
 use std::collections::BTreeMap;
-use std::fmt::Debug;
-use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use std::fmt::{self, Debug};
+use std::marker::PhantomData;
+use serde::{Serialize, Deserialize, Deserializer, de::{self, DeserializeOwned, DeserializeSeed, MapAccess, SeqAccess, Visitor}};
 use uuid::Uuid;
 
 use crate::player::{PlayerInfo, PlayerState};
@@ -15,6 +16,42 @@ pub struct GameInfo {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Variant<VariantParameters> {
     pub parameters: VariantParameters,
+    #[serde(default)]
+    pub time_control: TimeControl,
+}
+
+/// Time limits of the players, in seconds. No limit is enforced by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct TimeControl {
+    /// Time given to a player for each turn
+    pub per_turn: Option<u64>,
+    /// Total time given to each player for the whole game
+    pub per_game: Option<u64>,
+}
+
+impl TimeControl {
+    pub fn is_unlimited(&self) -> bool {
+        self.per_turn.is_none() && self.per_game.is_none()
+    }
+}
+
+/// State of the clocks of a game, saved with it so that the time keeps
+/// running while the game is not loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ClocksRecord {
+    /// Milliseconds left on the game clocks of the players who already played
+    pub game_remaining_ms: BTreeMap<Uuid, u64>,
+    /// Turn being played, `None` when nobody must play
+    pub turn: Option<TurnRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TurnRecord {
+    pub player_id: Uuid,
+    pub number: u64,
+    /// The time of the turn runs from this date, also while the game is not loaded
+    pub started_at: DateTime<Utc>,
+    pub timed_out: bool,
 }
 
 //Used for server diagnostics
@@ -24,23 +61,192 @@ pub struct GameExtendedInfo {
     pub players: Vec<Uuid>
 }
 
+/// Result of a finished game.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GameOutcome {
+    /// Sorted by rank
+    pub players: Vec<PlayerOutcome>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerOutcome {
+    pub player_id: Uuid,
+    pub score: i64,
+    /// 1 for the winner, players with the same score share the same rank
+    pub rank: u32,
+}
+
+impl GameOutcome {
+    /// Ranks the players by score, the highest score wins.
+    pub fn from_scores(scores: impl IntoIterator<Item = (Uuid, i64)>) -> GameOutcome {
+        let mut scores: Vec<(Uuid, i64)> = scores.into_iter().collect();
+        scores.sort_by(|(_, a), (_, b)| b.cmp(a));
+        let mut players: Vec<PlayerOutcome> = Vec::with_capacity(scores.len());
+        for (pos, (player_id, score)) in scores.into_iter().enumerate() {
+            let rank = match players.last() {
+                Some(previous) if previous.score == score => previous.rank,
+                _ => pos as u32 + 1,
+            };
+            players.push(PlayerOutcome { player_id, score, rank });
+        }
+        GameOutcome { players }
+    }
+}
+
 //Used for storing
-#[derive(Serialize, Deserialize, Debug, Clone)]
+//The schema version must stay the first field : it is needed to decode the state.
+//Once decoded, the state has been migrated to the current schema version.
+#[derive(Serialize, Debug, Clone)]
 pub struct GameRecord<State: GameState> {
+    pub schema_version: u32,
     pub date_updated: DateTime<Utc>,
     pub info: GameInfo,
-    #[serde(deserialize_with = "State::deserialize")] //cf. https://github.com/serde-rs/serde/issues/1296
     pub state: State,
+    /// Date the game ended, `None` for a game still running or abandoned
+    pub finished_at: Option<DateTime<Utc>>,
+    pub outcome: Option<GameOutcome>,
+    /// Variant the game was created with, `None` in the records written before it was stored
+    pub variant: Option<Variant<State::VariantParameters>>,
+    /// `None` for the games without time control
+    pub clocks: Option<ClocksRecord>,
 }
 
 impl<State: GameState> GameRecord<State> {
     pub fn create(state: State, info: GameInfo) -> Self {
         GameRecord { 
+            schema_version: State::SCHEMA_VERSION,
             date_updated: Utc::now(),
             info,
-            state
+            state,
+            finished_at: None,
+            outcome: None,
+            variant: None,
+            clocks: None,
         }
     }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished_at.is_some()
+    }
+}
+
+/// Decodes a game state stored with the given schema version, migrating it if needed.
+pub fn deserialize_state<'de, State: GameState, D: Deserializer<'de>>(schema_version: u32, deserializer: D) -> Result<State, D::Error> {
+    if schema_version == State::SCHEMA_VERSION {
+        State::deserialize(deserializer)
+    } else {
+        State::migrate(schema_version, deserializer)
+    }
+}
+
+struct StateSeed<State> {
+    schema_version: u32,
+    _phantom: PhantomData<State>,
+}
+
+impl<'de, State: GameState> DeserializeSeed<'de> for StateSeed<State> {
+    type Value = State;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<State, D::Error> {
+        deserialize_state(self.schema_version, deserializer)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum GameRecordField { SchemaVersion, DateUpdated, Info, State, FinishedAt, Outcome, Variant, Clocks, #[serde(other)] Other }
+
+// State of a record read from a map, which may come before the schema version
+enum PendingState<State> {
+    Decoded(State),
+    Buffered(serde_json::Value),
+}
+
+struct GameRecordVisitor<State>(PhantomData<State>);
+
+impl<'de, State: GameState> Visitor<'de> for GameRecordVisitor<State> {
+    type Value = GameRecord<State>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a game record")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let schema_version = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let date_updated = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let info = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        let state = seq.next_element_seed(StateSeed { schema_version, _phantom: PhantomData })?
+            .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+        let finished_at = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(4, &self))?;
+        let outcome = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(5, &self))?;
+        let variant = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(6, &self))?;
+        let clocks = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(7, &self))?;
+        Ok(GameRecord { schema_version: State::SCHEMA_VERSION, date_updated, info, state, finished_at, outcome, variant, clocks })
+    }
+
+    // Records written before versioning have no schema version : they are at version 0.
+    // The state is kept undecoded until the schema version is known.
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut schema_version = None;
+        let mut date_updated = None;
+        let mut info = None;
+        let mut state = None;
+        let mut finished_at = None;
+        let mut outcome = None;
+        let mut variant = None;
+        let mut clocks = None;
+        while let Some(field) = map.next_key()? {
+            match field {
+                GameRecordField::SchemaVersion => schema_version = Some(map.next_value()?),
+                GameRecordField::DateUpdated => date_updated = Some(map.next_value()?),
+                GameRecordField::Info => info = Some(map.next_value()?),
+                GameRecordField::State => state = Some(match schema_version {
+                    Some(schema_version) => PendingState::Decoded(map.next_value_seed(StateSeed { schema_version, _phantom: PhantomData })?),
+                    None => PendingState::Buffered(map.next_value::<serde_json::Value>()?),
+                }),
+                GameRecordField::FinishedAt => finished_at = map.next_value()?,
+                GameRecordField::Outcome => outcome = map.next_value()?,
+                GameRecordField::Variant => variant = map.next_value()?,
+                GameRecordField::Clocks => clocks = map.next_value()?,
+                GameRecordField::Other => { map.next_value::<de::IgnoredAny>()?; }
+            }
+        }
+        let state = match state.ok_or_else(|| de::Error::missing_field("state"))? {
+            PendingState::Decoded(state) => state,
+            PendingState::Buffered(value) => deserialize_state(schema_version.unwrap_or(0), value).map_err(de::Error::custom)?,
+        };
+        Ok(GameRecord {
+            schema_version: State::SCHEMA_VERSION,
+            date_updated: date_updated.ok_or_else(|| de::Error::missing_field("date_updated"))?,
+            info: info.ok_or_else(|| de::Error::missing_field("info"))?,
+            state,
+            finished_at,
+            outcome,
+            variant,
+            clocks,
+        })
+    }
+}
+
+const GAME_RECORD_FIELDS: &[&str] = &["schema_version", "date_updated", "info", "state", "finished_at", "outcome", "variant", "clocks"];
+
+impl<'de, State: GameState> Deserialize<'de> for GameRecord<State> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("GameRecord", GAME_RECORD_FIELDS, GameRecordVisitor(PhantomData))
+    }
+}
+
+/// Upgrades the game states stored by previous versions of a game.
+///
+/// Bump `SCHEMA_VERSION` on each incompatible change of the `GameState`
+/// structure, and decode the older versions in `migrate`.
+pub trait StateMigration: Sized {
+    const SCHEMA_VERSION: u32 = 0;
+
+    /// Decodes a state stored with schema version `from_version`.
+    fn migrate<'de, D: Deserializer<'de>>(from_version: u32, _deserializer: D) -> Result<Self, D::Error> {
+        Err(de::Error::custom(format!("no migration from schema version {}", from_version)))
+    }
 }
 // impl<State: GameState> From<State> for GameRecord<State> {
 //     fn from(state: State) -> Self {
@@ -53,13 +259,13 @@ impl<State: GameState> GameRecord<State> {
 
 
 //XXX is static lifetime a problem ?
-pub trait GameState: Sync+Default+Send+Serialize+DeserializeOwned+Clone+'static { 
+pub trait GameState: Sync+Default+Send+Serialize+DeserializeOwned+Clone+StateMigration+'static { 
     type PlayerPos: Send;
     type PlayerRole;
     type GamePlayerState: PlayerState;
     type Snapshot: GameStateSnapshot;
     type Operation: DebugOperation;
-    type VariantParameters;
+    type VariantParameters: Clone+Debug+Serialize+DeserializeOwned+Send+Sync;
 
     fn is_joinable(&self) -> bool;
     fn get_players(&self) -> &BTreeMap<Uuid, Self::GamePlayerState>;
@@ -69,10 +275,34 @@ pub trait GameState: Sync+Default+Send+Serialize+DeserializeOwned+Clone+'static
     fn get_player_role(&self, player_id: Uuid) -> Option<Self::PlayerRole>;
     fn player_by_pos(&self, position: Self::PlayerPos) -> Option<&Self::GamePlayerState>;
     fn make_snapshot(&self, player_id: Uuid) -> Self::Snapshot;
+    /// Snapshot sent to the spectators, it must not reveal any hidden information.
+    fn make_spectator_snapshot(&self) -> Self::Snapshot;
     fn set_player_ready(&mut self, player_id: Uuid) -> bool;
     fn update_init_state(&mut self) -> bool;
     fn set_player_not_ready(&mut self, player_id: Uuid);
 
+    /// Player who must play now, the only one whose clock runs.
+    fn current_player(&self) -> Option<Uuid> {
+        None
+    }
+    /// Number of turns played so far. A new turn starts when it changes, so
+    /// that a player who must play again gets the full turn time again.
+    fn turn_number(&self) -> u64 {
+        0
+    }
+    /// Called when the current player ran out of time, to play for them or
+    /// make them forfeit. It is not called again until the turn changes.
+    fn on_timeout(&mut self, _player_id: Uuid) {}
+
+    /// Tells if the game is over, the server then announces its outcome to the players.
+    fn is_finished(&self) -> bool {
+        false
+    }
+    /// Scores and ranks of the players, only called once the game is finished.
+    fn outcome(&self) -> GameOutcome {
+        GameOutcome::default()
+    }
+
     fn set_variant(&mut self, variant: Variant<Self::VariantParameters>);
     fn manage_operation(&mut self, operation: Self::Operation);
 
@@ -94,3 +324,66 @@ pub trait GameEventsListener<Event> {
 
 pub trait GameStateSnapshot: Debug+Serialize+DeserializeOwned+Send+Sync { }
 pub trait DebugOperation: Debug+Serialize+DeserializeOwned+Send+Sync { }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestState;
+
+    const GAME_ID: &str = "8d9ed1e5-3c8c-4a36-9e3b-0f5f4d2a7c11";
+
+    #[test]
+    fn outcome_ranks_tied_players_together() {
+        let ids: Vec<Uuid> = (1..=4).map(Uuid::from_u128).collect();
+        let outcome = GameOutcome::from_scores(vec![(ids[0], 5), (ids[1], 12), (ids[2], 5), (ids[3], 1)]);
+        let ranks: Vec<(i64, u32)> = outcome.players.iter().map(|player| (player.score, player.rank)).collect();
+        assert_eq!(ranks, vec![(12, 1), (5, 2), (5, 2), (1, 4)]);
+        assert_eq!(outcome.players[0].player_id, ids[1]);
+        assert_eq!(outcome.players[3].player_id, ids[3]);
+    }
+
+    #[test]
+    fn outcome_of_a_draw() {
+        let outcome = GameOutcome::from_scores(vec![(Uuid::from_u128(1), 3), (Uuid::from_u128(2), 3)]);
+        assert!(outcome.players.iter().all(|player| player.rank == 1));
+        assert!(GameOutcome::from_scores(vec![]).players.is_empty());
+    }
+
+    #[test]
+    fn json_record_without_schema_version_is_migrated() {
+        let json = format!(r#"{{
+            "date_updated": "2021-03-01T10:00:00Z",
+            "info": {{ "game_id": "{}", "join_code": "BCDFGH" }},
+            "state": {{ "players": {{}}, "points": 12 }}
+        }}"#, GAME_ID);
+        let record: GameRecord<TestState> = serde_json::from_str(&json).unwrap();
+        assert_eq!(record.schema_version, 1);
+        assert_eq!(record.state.score, 12);
+        assert_eq!(record.info.join_code, "BCDFGH");
+        assert!(record.finished_at.is_none() && record.variant.is_none());
+    }
+
+    #[test]
+    fn json_record_with_state_before_schema_version() {
+        let json = format!(r#"{{
+            "state": {{ "players": {{}}, "score": 5 }},
+            "info": {{ "game_id": "{}", "join_code": "BCDFGH" }},
+            "date_updated": "2021-03-01T10:00:00Z",
+            "schema_version": 1
+        }}"#, GAME_ID);
+        let record: GameRecord<TestState> = serde_json::from_str(&json).unwrap();
+        assert_eq!(record.state.score, 5);
+    }
+
+    #[test]
+    fn json_record_round_trip() {
+        let info = GameInfo { game_id: Uuid::parse_str(GAME_ID).unwrap(), join_code: "BCDFGH".into() };
+        let mut record = GameRecord::create(TestState { score: 7, ..Default::default() }, info);
+        record.finished_at = Some(record.date_updated);
+        record.variant = Some(Variant { parameters: 0, time_control: TimeControl { per_turn: Some(30), per_game: None } });
+        let decoded: GameRecord<TestState> = serde_json::from_str(&serde_json::to_string(&record).unwrap()).unwrap();
+        assert_eq!(decoded.state.score, 7);
+        assert_eq!(decoded.finished_at, record.finished_at);
+        assert_eq!(decoded.variant.unwrap().time_control.per_turn, Some(30));
+    }
+}
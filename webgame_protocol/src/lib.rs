@@ -1,6 +1,8 @@
 mod game;
 mod message;
 mod player;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 pub use crate::game::*;
 pub use crate::message::*;
@@ -1,18 +1,36 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use json_patch::Patch;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::game::{GameInfo, GameExtendedInfo, GameRecord, GameState};
+use crate::game::{GameInfo, GameExtendedInfo, GameOutcome, GameRecord, GameState};
 use crate::player::PlayerInfo;
 
+/// Version of the protocol spoken by this crate.
+///
+/// It is increased on incompatible changes of the commands or messages.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version still accepted by the server.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "cmd", rename_all = "snake_case")]
 pub enum Command<GamePlayCommand, SetPlayerRoleCommand, GameStateSnapshot, DebugOperation, VariantCommand> {
     Ping,
+    Hello(HelloCommand),
     Authenticate(AuthenticateCommand),
     SendText(SendTextCommand),
     NewGame(VariantCommand),
     JoinGame(JoinGameCommand),
+    Spectate(JoinGameCommand), // watch a game by join code, without taking a seat
+    ResumeGame(ResumeGameCommand),
+    Replay(ReplayCommand), // send again the play events and chat messages missed while disconnected
+    RequestResync, // ask for a full snapshot, when a delta does not apply to the last state received
     LeaveGame,
+    Rematch, // accept to play again with the players of the finished game
     MarkReady,
     InviteBot,
     Continue,
@@ -20,11 +38,38 @@ pub enum Command<GamePlayCommand, SetPlayerRoleCommand, GameStateSnapshot, Debug
     GamePlay(GamePlayCommand),
     SetPlayerRole(SetPlayerRoleCommand),
 
+    AdminLogin(AdminLoginCommand), // gives the admin role to the connection, needed by the commands below
     DebugUi(DebugUiCommand<GameStateSnapshot>), // Used to send a custom state to a client, allows to quickly view the UI at a given state of the game without having to play all the hands leading to this state.
     DebugGame(DebugGameCommand<DebugOperation>), // Send an operation to the game
     ShowUuid, // get uuid of connected client : for use with debugUi
     ShowServerStatus, // get server infos : active games, players connected...
     ShowServerGames, // get stored games
+    ImportArchive(ImportArchiveCommand), // load an archived game back into the server
+}
+
+/// A command with an optional request id.
+///
+/// When the client sets `req_id`, the server echoes it in the `ack`
+/// message sent once the command succeeded, or in the error it produced.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandEnvelope<CommandT> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub req_id: Option<u64>,
+    #[serde(flatten)]
+    pub command: CommandT,
+}
+
+/// A message with its sequence number.
+///
+/// Sequence numbers are sent to the clients which enabled the `replay`
+/// capability. They increase with each message sent to the user and are
+/// kept across reconnections.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageEnvelope<MessageT> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub message: MessageT,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Hash)]
@@ -44,12 +89,19 @@ pub enum ProtocolErrorKind {
     BadInput,
     /// This should never happen.
     InternalError,
+    /// The client speaks a protocol version the server does not support
+    UnsupportedVersion,
+    /// The command needs the admin role, or is disabled on this server
+    Forbidden,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProtocolError {
     kind: ProtocolErrorKind,
     message: String,
+    /// Request id of the command which failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    req_id: Option<u64>,
 }
 
 impl ProtocolError {
@@ -57,9 +109,15 @@ impl ProtocolError {
         ProtocolError {
             kind,
             message: s.into(),
+            req_id: None,
         }
     }
 
+    pub fn with_req_id(mut self, req_id: Option<u64>) -> ProtocolError {
+        self.req_id = req_id;
+        self
+    }
+
     pub fn kind(&self) -> ProtocolErrorKind {
         self.kind
     }
@@ -67,6 +125,22 @@ impl ProtocolError {
     pub fn message(&self) -> &str {
         &self.message
     }
+
+    pub fn req_id(&self) -> Option<u64> {
+        self.req_id
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelloCommand {
+    pub protocol_version: u32,
+    pub client_name: String,
+    /// Optional features the client wants to use
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Encoding of the following commands and messages : json, msgpack or cbor
+    #[serde(default)]
+    pub codec: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -74,6 +148,20 @@ pub struct AuthenticateCommand {
     pub nickname: String,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AdminLoginCommand {
+    pub token: String,
+}
+
+// The token must not end up in the logs
+impl fmt::Debug for AdminLoginCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminLoginCommand")
+         .field("token", &"<redacted>")
+         .finish()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DebugGameCommand<DebugOperation> {
     pub game_id: Uuid,
@@ -86,6 +174,27 @@ pub struct DebugUiCommand<GameStateSnapshot> {
     pub snapshot: GameStateSnapshot,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportArchiveCommand {
+    pub game_id: Uuid,
+    pub mode: ImportMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// The game can be viewed but no longer played
+    ReadOnly,
+    /// The game can be played again and is saved in the store
+    Resumable,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayCommand {
+    /// Sequence number of the last message received by the client
+    pub last_seq: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SendTextCommand {
     pub text: String,
@@ -96,6 +205,15 @@ pub struct JoinGameCommand {
     pub join_code: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResumeGameCommand {
+    pub join_code: String,
+    /// Id the returning player had in the game
+    pub player_id: Uuid,
+    /// Session token issued to the player
+    pub token: String,
+}
+
 // TODO : read https://serde.rs/lifetimes.html
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -106,19 +224,30 @@ pub enum Message<
     DebugOperationT: Send,
     PlayEventT: Send> {
     Connected,
+    Welcome(WelcomeMessage),
     Pong,
+    Ack(AckMessage),
     ServerStatus(ServerStatus),
+    ArchiveImported(GameExtendedInfo),
     // ServerStoredGames(ServerStoredGames<GamePlayerStateT>),
     Chat(ChatMessage),
     PlayerConnected(GamePlayerStateT),
     PlayerDisconnected(PlayerDisconnectedMessage),
     PregameStarted,
     GameJoined(GameInfo),
+    Spectating(GameInfo),
+    SpectatorChat(SpectatorChatMessage),
     GameLeft,
+    GameFinished(GameFinishedMessage),
+    RematchAccepted(RematchAcceptedMessage),
+    Clock(ClockMessage),
     Authenticated(PlayerInfo),
+    AdminAuthenticated,
+    SessionToken(SessionTokenMessage),
     Error(ProtocolError),
     PlayEvent(PlayEventT),
     GameStateSnapshot(GameStateSnapshotT),
+    GameStateDelta(GameStateDelta),
     DebugOperation(DebugOperationT)
 }
 
@@ -127,18 +256,98 @@ pub enum Message<
 //     pub games: Vec<GameRecord<StateT>>,
 // }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WelcomeMessage {
+    pub protocol_version: u32,
+    pub server_version: String,
+    /// Optional features supported by the server, those also requested by
+    /// the client are enabled for the connection
+    pub capabilities: Vec<String>,
+    /// Encoding used for the following commands and messages
+    pub codec: String,
+    /// Encodings supported by the server, by order of preference
+    #[serde(default)]
+    pub codecs: Vec<String>,
+}
+
+/// Token proving the identity of the user when reconnecting.
+///
+/// It is given in the `token` query parameter of the websocket url, or in
+/// the `resume_game` command.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionTokenMessage {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameFinishedMessage {
+    pub finished_at: DateTime<Utc>,
+    pub outcome: GameOutcome,
+}
+
+/// A player accepted a rematch, it starts once more than half of the players accepted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RematchAcceptedMessage {
+    pub player_id: Uuid,
+    pub accepted: usize,
+    pub players: usize,
+}
+
+/// State of the clocks, sent when the turn changes. The clients count down
+/// from these values until the next clock message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClockMessage {
+    /// Player whose clock runs, `None` when nobody must play
+    pub player_id: Option<Uuid>,
+    /// Milliseconds left to the current player before its turn times out
+    pub turn_remaining_ms: Option<u64>,
+    /// Milliseconds left on the game clock of each player, empty without a per game limit
+    pub game_remaining_ms: BTreeMap<Uuid, u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AckMessage {
+    pub req_id: u64,
+}
+
+/// Changes since the last snapshot sent to the client, as a JSON patch.
+///
+/// A full snapshot is version 0, each delta increases the version. The
+/// patch applies to the state of version `base_version` only, the client
+/// sends a `request_resync` command when it does not have this version.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameStateDelta {
+    pub base_version: u64,
+    pub version: u64,
+    pub patch: Patch,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerStatus {
     pub players: Vec<Uuid>,
     pub games: Vec<GameExtendedInfo>,
+    /// Number of failed store operations since the server started
+    #[serde(default)]
+    pub store_errors: u64,
 }
 
+/// Chat of a player, sent to the players and the spectators of the game.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatMessage {
     pub player_id: Uuid,
     pub text: String,
 }
 
+/// Chat between the spectators of a game, the players do not receive it so
+/// that spectators can not give hints to them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpectatorChatMessage {
+    pub spectator_id: Uuid,
+    pub nickname: String,
+    pub text: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PlayerDisconnectedMessage {
     pub player_id: Uuid,
@@ -0,0 +1,98 @@
+//! A small game used by the unit tests, enabled by the `test-util` feature.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer, Serialize, de};
+use uuid::Uuid;
+
+use crate::game::{DebugOperation, GameOutcome, GameState, GameStateSnapshot, StateMigration, Variant};
+use crate::player::{PlayerInfo, PlayerState};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TestPlayer {
+    pub info: PlayerInfo,
+}
+
+impl PlayerState for TestPlayer {
+    fn player(self) -> PlayerInfo {
+        self.info
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TestSnapshot {
+    pub score: u32,
+    pub players: Vec<PlayerInfo>,
+}
+impl GameStateSnapshot for TestSnapshot {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TestOperation;
+impl DebugOperation for TestOperation {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TestState {
+    pub players: BTreeMap<Uuid, TestPlayer>,
+    pub score: u32,
+    #[serde(default)]
+    pub finished: bool,
+    /// Players who ran out of time
+    #[serde(default)]
+    pub timed_out: Vec<Uuid>,
+}
+
+/// The state of schema version 0, the score was named `points`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TestStateV0 {
+    pub players: BTreeMap<Uuid, TestPlayer>,
+    pub points: u32,
+}
+
+impl StateMigration for TestState {
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn migrate<'de, D: Deserializer<'de>>(from_version: u32, deserializer: D) -> Result<Self, D::Error> {
+        match from_version {
+            0 => TestStateV0::deserialize(deserializer).map(|state| TestState { players: state.players, score: state.points, ..Default::default() }),
+            _ => Err(de::Error::custom(format!("no migration from schema version {}", from_version))),
+        }
+    }
+}
+
+impl GameState for TestState {
+    type PlayerPos = Uuid;
+    type PlayerRole = ();
+    type GamePlayerState = TestPlayer;
+    type Snapshot = TestSnapshot;
+    type Operation = TestOperation;
+    type VariantParameters = u32;
+
+    fn is_joinable(&self) -> bool { true }
+    fn get_players(&self) -> &BTreeMap<Uuid, TestPlayer> { &self.players }
+    fn add_player(&mut self, info: PlayerInfo) -> Uuid {
+        let id = info.id;
+        self.players.insert(id, TestPlayer { info });
+        id
+    }
+    fn remove_player(&mut self, player_id: Uuid) -> bool { self.players.remove(&player_id).is_some() }
+    fn set_player_role(&mut self, _player_id: Uuid, _role: ()) {}
+    fn get_player_role(&self, _player_id: Uuid) -> Option<()> { None }
+    fn player_by_pos(&self, position: Uuid) -> Option<&TestPlayer> { self.players.get(&position) }
+    fn make_snapshot(&self, _player_id: Uuid) -> TestSnapshot {
+        TestSnapshot { score: self.score, players: self.players.values().map(|player| player.info.clone()).collect() }
+    }
+    fn make_spectator_snapshot(&self) -> TestSnapshot { self.make_snapshot(Uuid::nil()) }
+    fn set_player_ready(&mut self, _player_id: Uuid) -> bool { false }
+    fn update_init_state(&mut self) -> bool { false }
+    fn set_player_not_ready(&mut self, _player_id: Uuid) {}
+    // The first player always plays
+    fn current_player(&self) -> Option<Uuid> { self.players.keys().next().copied() }
+    fn on_timeout(&mut self, player_id: Uuid) { self.timed_out.push(player_id); }
+    fn is_finished(&self) -> bool { self.finished }
+    // Every player gets the score
+    fn outcome(&self) -> GameOutcome {
+        GameOutcome::from_scores(self.players.keys().map(|player_id| (*player_id, self.score as i64)))
+    }
+    fn set_variant(&mut self, variant: Variant<u32>) { self.score = variant.parameters; }
+    fn manage_operation(&mut self, _operation: TestOperation) {}
+}
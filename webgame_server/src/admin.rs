@@ -0,0 +1,24 @@
+/// Access to the administration commands.
+///
+/// A connection gets the admin role by sending the admin token with the
+/// `admin_login` command. The commands modifying games or sending fake states
+/// to the players are only enabled in debug mode.
+#[derive(Debug, Clone, Default)]
+pub struct AdminSettings {
+    /// Token giving the admin role, nobody can be admin when `None`
+    pub token: Option<String>,
+    /// Enables the `debug_ui` and `debug_game` commands
+    pub debug: bool,
+}
+
+impl AdminSettings {
+    /// Checks the token given by a client.
+    pub fn check_token(&self, token: &str) -> bool {
+        match &self.token {
+            // Compares every byte so that the time taken does not tell how much of the token matched
+            Some(expected) => expected.len() == token.len()
+                && expected.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0,
+            None => false,
+        }
+    }
+}
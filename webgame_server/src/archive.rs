@@ -0,0 +1,408 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::executor::block_on;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::protocol::{GameInfo, GameState, GameRecord};
+use crate::store::run_blocking;
+use crate::universe::Universe;
+use crate::utils::write_atomically;
+
+/// Destination of the archived games.
+pub trait ArchiveSink<GameStateT: GameState>: Send + Sync {
+    /// Writes the record in the archives.
+    fn write(&self, record: &GameRecord<GameStateT>) -> io::Result<()>;
+
+    /// Reads the archived record of a game.
+    fn load(&self, game_id: Uuid) -> io::Result<Option<GameRecord<GameStateT>>>;
+
+    /// Directory where the archives are written.
+    fn directory(&self) -> &Path;
+
+    /// End of the names of the archive files, such as `.json`.
+    fn suffix(&self) -> &str;
+
+    /// Removes the archive files last modified before `date`.
+    ///
+    /// Other files of the directory, like the temporary files of interrupted
+    /// writes, are left. Returns the number of files removed.
+    fn prune(&self, date: DateTime<Utc>) -> io::Result<usize> {
+        let limit = SystemTime::from(date);
+        let mut removed = 0;
+        for entry in fs::read_dir(self.directory())? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') || !name.ends_with(self.suffix()) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && metadata.modified()? < limit {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Format of the archives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    /// One `{game_id}.json` file per game
+    Json,
+    /// One `{game_id}.json.gz` file per game
+    GzipJson,
+    /// One `{date}.jsonl` file per day, with a game record per line
+    Daily,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ArchiveFormat::Json),
+            "gzip" => Ok(ArchiveFormat::GzipJson),
+            "daily" => Ok(ArchiveFormat::Daily),
+            _ => Err(format!("unknown archive format '{}'", s)),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    /// Creates the sink writing this format in `directory`.
+    pub fn sink<GameStateT: GameState>(&self, directory: &str) -> io::Result<Arc<dyn ArchiveSink<GameStateT>>> {
+        fs::create_dir_all(directory)?;
+        let directory = PathBuf::from(directory);
+        Ok(match self {
+            ArchiveFormat::Json => Arc::new(JsonArchive { directory, _phantom: PhantomData }),
+            ArchiveFormat::GzipJson => Arc::new(GzipJsonArchive { directory, _phantom: PhantomData }),
+            ArchiveFormat::Daily => Arc::new(DailyArchive { directory, _phantom: PhantomData }),
+        })
+    }
+}
+
+fn read_json_file<GameStateT: GameState>(path: &Path, gzipped: bool) -> io::Result<Option<GameRecord<GameStateT>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file = File::open(path)?;
+    let record = if gzipped {
+        serde_json::from_reader(BufReader::new(GzDecoder::new(file)))?
+    } else {
+        serde_json::from_reader(BufReader::new(file))?
+    };
+    Ok(Some(record))
+}
+
+pub struct JsonArchive<GameStateT> {
+    directory: PathBuf,
+    _phantom: PhantomData<GameStateT>,
+}
+
+impl<GameStateT: GameState> ArchiveSink<GameStateT> for JsonArchive<GameStateT> {
+    fn write(&self, record: &GameRecord<GameStateT>) -> io::Result<()> {
+        let path = self.directory.join(format!("{}.json", record.info.game_id));
+        write_atomically(&path, |writer| Ok(serde_json::to_writer(writer, record)?))
+    }
+
+    fn load(&self, game_id: Uuid) -> io::Result<Option<GameRecord<GameStateT>>> {
+        read_json_file(&self.directory.join(format!("{}.json", game_id)), false)
+    }
+
+    fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn suffix(&self) -> &str {
+        ".json"
+    }
+}
+
+pub struct GzipJsonArchive<GameStateT> {
+    directory: PathBuf,
+    _phantom: PhantomData<GameStateT>,
+}
+
+impl<GameStateT: GameState> ArchiveSink<GameStateT> for GzipJsonArchive<GameStateT> {
+    fn write(&self, record: &GameRecord<GameStateT>) -> io::Result<()> {
+        let path = self.directory.join(format!("{}.json.gz", record.info.game_id));
+        write_atomically(&path, |writer| {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            serde_json::to_writer(&mut encoder, record)?;
+            encoder.finish()?;
+            Ok(())
+        })
+    }
+
+    fn load(&self, game_id: Uuid) -> io::Result<Option<GameRecord<GameStateT>>> {
+        read_json_file(&self.directory.join(format!("{}.json.gz", game_id)), true)
+    }
+
+    fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn suffix(&self) -> &str {
+        ".json.gz"
+    }
+}
+
+pub struct DailyArchive<GameStateT> {
+    directory: PathBuf,
+    _phantom: PhantomData<GameStateT>,
+}
+
+impl<GameStateT: GameState> ArchiveSink<GameStateT> for DailyArchive<GameStateT> {
+    fn write(&self, record: &GameRecord<GameStateT>) -> io::Result<()> {
+        let path = self.directory.join(format!("{}.jsonl", Utc::now().format("%Y-%m-%d")));
+        // The line is written in a single call so that records are not interleaved
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&line)?;
+        file.sync_all()
+    }
+
+    // The bundles are searched from the most recent one, only the matching
+    // line is fully decoded
+    fn load(&self, game_id: Uuid) -> io::Result<Option<GameRecord<GameStateT>>> {
+        #[derive(Deserialize)]
+        struct RecordInfo {
+            info: GameInfo,
+        }
+
+        let mut bundles: Vec<PathBuf> = fs::read_dir(&self.directory)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+            .collect();
+        bundles.sort();
+        for bundle in bundles.iter().rev() {
+            for line in BufReader::new(File::open(bundle)?).lines() {
+                let line = line?;
+                let found = serde_json::from_str::<RecordInfo>(&line)
+                    .is_ok_and(|record| record.info.game_id == game_id);
+                if found {
+                    return Ok(Some(serde_json::from_str(&line)?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn suffix(&self) -> &str {
+        ".jsonl"
+    }
+}
+
+/// When the games are archived, and how long the archives are kept.
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+    /// Finished or abandoned games not updated for this long are moved to the archives
+    pub archive_after: chrono::Duration,
+    /// Period between two archival passes
+    pub check_interval: Duration,
+    /// Archives older than this are deleted, they are kept forever when `None`
+    pub retention: Option<chrono::Duration>,
+}
+
+/// Moves the games which are over from the store to the archives.
+///
+/// Only the finished games and the games all the players left are archived,
+/// the others can still be resumed : they stay in the store however long
+/// they are idle. The games loaded in the universe are left in the store,
+/// they are archived once evicted. Runs until `shutdown` is set to `true`.
+pub async fn run<GameStateT: GameState, PlayEventT: Serialize+Send>(
+    universe: Arc<Universe<GameStateT, PlayEventT>>,
+    sink: Arc<dyn ArchiveSink<GameStateT>>,
+    config: ArchiveConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        archive_games(&universe, &sink, &config).await;
+        if let Some(retention) = config.retention {
+            let prune_sink = sink.clone();
+            let pruned = tokio::task::spawn_blocking(move || prune_sink.prune(Utc::now() - retention)).await;
+            match pruned {
+                Ok(Ok(removed)) => if removed > 0 { debug!("{} archives removed", removed) },
+                Ok(Err(err)) => error!("could not remove old archives: {}", err),
+                Err(err) => error!("could not remove old archives: {}", err),
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(config.check_interval) => {}
+            _ = shutdown.changed() => {}
+        }
+        if *shutdown.borrow() {
+            debug!("archive task stopped");
+            return;
+        }
+    }
+}
+
+async fn archive_games<GameStateT: GameState, PlayEventT: Serialize+Send>(
+    universe: &Universe<GameStateT, PlayEventT>,
+    sink: &Arc<dyn ArchiveSink<GameStateT>>,
+    config: &ArchiveConfig,
+) {
+    let limit = Utc::now() - config.archive_after;
+    let store = universe.store();
+    let records = match run_blocking(store, move |store| block_on(store.list_updated_before(limit))).await {
+        Ok(records) => records,
+        Err(err) => {
+            universe.report_store_error("list games to archive", &err);
+            return;
+        }
+    };
+    debug!("{} games not updated since {}", records.len(), limit);
+
+    for record in records {
+        let game_id = record.info.game_id;
+        let abandoned = record.state.get_players().is_empty();
+        if !record.is_finished() && !abandoned {
+            continue;
+        }
+        // Its record would be written back on the next save
+        if universe.get_game(game_id).await.is_some() {
+            continue;
+        }
+        let join_code = record.info.join_code.clone();
+        let status = if record.is_finished() { "finished" } else { "abandoned" };
+        let write_sink = sink.clone();
+        let written = tokio::task::spawn_blocking(move || write_sink.write(&record)).await;
+        match written {
+            Ok(Ok(())) => {
+                debug!("archived {} game {}", status, game_id);
+                match run_blocking(store, move |store| block_on(store.delete(game_id))).await {
+                    Ok(()) => universe.release_join_code(&join_code, game_id).await,
+                    Err(err) => universe.report_store_error(&format!("delete archived game {}", game_id), &err),
+                }
+            }
+            Ok(Err(err)) => error!("could not archive game {}: {}", game_id, err),
+            Err(err) => error!("could not archive game {}: {}", game_id, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::AdminSettings;
+    use crate::protocol::{ImportMode, PlayerInfo, ProtocolErrorKind};
+    use crate::protocol::test_util::{TestPlayer, TestState};
+    use crate::session::Sessions;
+    use crate::store::{GameStore, MemoryStore};
+
+    fn make_universe(store: Arc<MemoryStore<TestState>>, sink: Arc<dyn ArchiveSink<TestState>>) -> Arc<Universe<TestState, ()>> {
+        let sessions = Sessions::with_random_secret(chrono::Duration::hours(1));
+        Arc::new(Universe::new(store, Some(sink), sessions, AdminSettings::default(), String::new()))
+    }
+
+    fn config() -> ArchiveConfig {
+        ArchiveConfig { archive_after: chrono::Duration::days(1), check_interval: Duration::from_secs(60), retention: None }
+    }
+
+    // Record of a game last updated two days ago
+    fn old_record(players: usize, finished: bool) -> GameRecord<TestState> {
+        let mut state = TestState::default();
+        for _ in 0..players {
+            let info = PlayerInfo { id: Uuid::new_v4(), nickname: "player".into() };
+            state.players.insert(info.id, TestPlayer { info });
+        }
+        let mut record = GameRecord::create(state, GameInfo { game_id: Uuid::new_v4(), join_code: "BCDFGH".into() });
+        record.date_updated = Utc::now() - chrono::Duration::days(2);
+        record.finished_at = Some(record.date_updated).filter(|_| finished);
+        record
+    }
+
+    #[tokio::test]
+    async fn archives_finished_and_abandoned_games_only() {
+        let directory = tempfile::tempdir().unwrap();
+        let sink = ArchiveFormat::Json.sink::<TestState>(directory.path().to_str().unwrap()).unwrap();
+        let store = Arc::new(MemoryStore::new("memory://"));
+        let universe = make_universe(store.clone(), sink.clone());
+        let (running, finished, abandoned) = (old_record(2, false), old_record(2, true), old_record(0, false));
+        let (running_id, finished_id, abandoned_id) = (running.info.game_id, finished.info.game_id, abandoned.info.game_id);
+        for record in [running, finished, abandoned] {
+            store.save(record).await.unwrap();
+        }
+
+        archive_games(&universe, &sink, &config()).await;
+
+        // An idle game can still be resumed
+        assert!(store.load(running_id).await.is_ok());
+        assert!(sink.load(running_id).unwrap().is_none());
+        for game_id in [finished_id, abandoned_id] {
+            assert!(store.load(game_id).await.is_err());
+            assert!(sink.load(game_id).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn writes_loads_and_prunes_every_format() {
+        for format in [ArchiveFormat::Json, ArchiveFormat::GzipJson, ArchiveFormat::Daily] {
+            let directory = tempfile::tempdir().unwrap();
+            let sink = format.sink::<TestState>(directory.path().to_str().unwrap()).unwrap();
+            let record = old_record(2, true);
+            let game_id = record.info.game_id;
+            sink.write(&record).unwrap();
+            assert_eq!(sink.load(game_id).unwrap().unwrap().state.players.len(), 2, "{:?}", format);
+            assert!(sink.load(Uuid::new_v4()).unwrap().is_none(), "{:?}", format);
+
+            // Interrupted writes and other files are not archives
+            let others = [directory.path().join(format!(".partial{}", sink.suffix())), directory.path().join("notes.txt")];
+            for other in &others {
+                fs::write(other, "").unwrap();
+            }
+            assert_eq!(sink.prune(Utc::now() - chrono::Duration::hours(1)).unwrap(), 0, "{:?}", format);
+            assert_eq!(sink.prune(Utc::now() + chrono::Duration::hours(1)).unwrap(), 1, "{:?}", format);
+            assert!(sink.load(game_id).unwrap().is_none(), "{:?}", format);
+            assert!(others.iter().all(|other| other.exists()), "{:?}", format);
+        }
+    }
+
+    #[tokio::test]
+    async fn imports_archived_games_until_they_are_pruned() {
+        let directory = tempfile::tempdir().unwrap();
+        let sink = ArchiveFormat::GzipJson.sink::<TestState>(directory.path().to_str().unwrap()).unwrap();
+        let store = Arc::new(MemoryStore::new("memory://"));
+        let universe = make_universe(store.clone(), sink.clone());
+        let record = old_record(2, true);
+        let game_id = record.info.game_id;
+        store.save(record).await.unwrap();
+        archive_games(&universe, &sink, &config()).await;
+        assert!(store.is_empty());
+
+        let game = universe.import_archive(game_id, ImportMode::ReadOnly).await.unwrap();
+        assert!(game.is_read_only());
+        assert_eq!(game.state_handle().lock().await.players.len(), 2);
+        universe.remove_game(game_id).await;
+
+        let game = universe.import_archive(game_id, ImportMode::Resumable).await.unwrap();
+        assert!(!game.is_read_only());
+        universe.flush_dirty_games().await;
+        assert_eq!(store.load(game_id).await.unwrap().info.join_code, game.join_code());
+        universe.remove_game(game_id).await;
+
+        assert_eq!(sink.prune(Utc::now() + chrono::Duration::hours(1)).unwrap(), 1);
+        let err = universe.import_archive(game_id, ImportMode::ReadOnly).await.unwrap_err();
+        assert_eq!(err.kind(), ProtocolErrorKind::NotFound);
+    }
+}
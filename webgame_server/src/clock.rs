@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::protocol::{ClockMessage, ClocksRecord, GameState, TimeControl, TurnRecord};
+use crate::universe::Universe;
+
+// Period between two checks of the deadlines
+const CHECK_PERIOD: Duration = Duration::from_millis(250);
+
+/// Clocks of the players of a game.
+///
+/// Only the clock of the current player runs. A turn ends when another
+/// player must play, or when the turn number given by the game state changes.
+pub struct Clocks {
+    time_control: TimeControl,
+    // Game clocks of the players who already played, the others have the full time
+    game_remaining: HashMap<Uuid, Duration>,
+    turn: Option<Turn>,
+}
+
+struct Turn {
+    player_id: Uuid,
+    number: u64,
+    started: Instant,
+    // Time spent before the clocks were restored from a record
+    elapsed_before: Duration,
+    timed_out: bool,
+}
+
+impl Turn {
+    fn elapsed(&self, now: Instant) -> Duration {
+        self.elapsed_before + now.saturating_duration_since(self.started)
+    }
+}
+
+impl Clocks {
+    pub fn new(time_control: TimeControl) -> Clocks {
+        Clocks {
+            time_control,
+            game_remaining: HashMap::new(),
+            turn: None,
+        }
+    }
+
+    // Time left on the game clock of a player, not counting the current turn
+    fn game_remaining(&self, player_id: Uuid) -> Option<Duration> {
+        let per_game = Duration::from_secs(self.time_control.per_game?);
+        Some(self.game_remaining.get(&player_id).copied().unwrap_or(per_game))
+    }
+
+    // Time left to the current player before it times out
+    fn turn_remaining(&self, turn: &Turn, now: Instant) -> Option<Duration> {
+        let elapsed = turn.elapsed(now);
+        let per_turn = self.time_control.per_turn.map(|secs| Duration::from_secs(secs).saturating_sub(elapsed));
+        let per_game = self.game_remaining(turn.player_id).map(|remaining| remaining.saturating_sub(elapsed));
+        match (per_turn, per_game) {
+            (Some(per_turn), Some(per_game)) => Some(per_turn.min(per_game)),
+            (per_turn, per_game) => per_turn.or(per_game),
+        }
+    }
+
+    // Takes the time spent from the game clock of the current player
+    fn end_turn(&mut self, now: Instant) {
+        if let Some(turn) = self.turn.take() {
+            if let Some(remaining) = self.game_remaining(turn.player_id) {
+                let elapsed = turn.elapsed(now);
+                self.game_remaining.insert(turn.player_id, remaining.saturating_sub(elapsed));
+            }
+        }
+    }
+
+    /// Starts the turn `number` of the player who must play.
+    ///
+    /// Returns false if it is still the same turn.
+    pub fn set_turn(&mut self, player_id: Option<Uuid>, number: u64, now: Instant) -> bool {
+        let current = self.turn.as_ref().map(|turn| (turn.player_id, turn.number));
+        if current == player_id.map(|player_id| (player_id, number)) {
+            return false;
+        }
+        self.end_turn(now);
+        self.turn = player_id.map(|player_id| Turn { player_id, number, started: now, elapsed_before: Duration::ZERO, timed_out: false });
+        true
+    }
+
+    /// Returns the current player and turn number if it just ran out of time.
+    pub fn take_timeout(&mut self, now: Instant) -> Option<(Uuid, u64)> {
+        let turn = self.turn.as_ref().filter(|turn| !turn.timed_out)?;
+        if !self.turn_remaining(turn, now)?.is_zero() {
+            return None;
+        }
+        let turn = self.turn.as_mut()?;
+        turn.timed_out = true;
+        Some((turn.player_id, turn.number))
+    }
+
+    pub fn message(&self, player_ids: impl Iterator<Item = Uuid>, now: Instant) -> ClockMessage {
+        let elapsed = |player_id: Uuid| match &self.turn {
+            Some(turn) if turn.player_id == player_id => turn.elapsed(now),
+            _ => Duration::ZERO,
+        };
+        ClockMessage {
+            player_id: self.turn.as_ref().map(|turn| turn.player_id),
+            turn_remaining_ms: self.turn.as_ref()
+                .and_then(|turn| self.turn_remaining(turn, now))
+                .map(|remaining| remaining.as_millis() as u64),
+            game_remaining_ms: player_ids
+                .filter_map(|player_id| {
+                    let remaining = self.game_remaining(player_id)?.saturating_sub(elapsed(player_id));
+                    Some((player_id, remaining.as_millis() as u64))
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the state of the clocks to save with the game, `date` being the date at `now`.
+    pub fn record(&self, now: Instant, date: DateTime<Utc>) -> ClocksRecord {
+        ClocksRecord {
+            game_remaining_ms: self.game_remaining.iter()
+                .map(|(player_id, remaining)| (*player_id, remaining.as_millis() as u64))
+                .collect(),
+            turn: self.turn.as_ref().map(|turn| TurnRecord {
+                player_id: turn.player_id,
+                number: turn.number,
+                started_at: date - chrono::Duration::from_std(turn.elapsed(now)).unwrap_or_else(|_| chrono::Duration::zero()),
+                timed_out: turn.timed_out,
+            }),
+        }
+    }
+
+    /// Restores the clocks saved with a game, `date` being the date at `now`.
+    ///
+    /// The current turn goes on : the time elapsed since it started, even
+    /// while the game was not loaded, is counted.
+    pub fn restore(mut self, record: &ClocksRecord, now: Instant, date: DateTime<Utc>) -> Clocks {
+        self.game_remaining = record.game_remaining_ms.iter()
+            .map(|(player_id, remaining)| (*player_id, Duration::from_millis(*remaining)))
+            .collect();
+        self.turn = record.turn.as_ref().map(|turn| Turn {
+            player_id: turn.player_id,
+            number: turn.number,
+            started: now,
+            elapsed_before: (date - turn.started_at).to_std().unwrap_or_default(),
+            timed_out: turn.timed_out,
+        });
+        self
+    }
+}
+
+/// Calls `GameState::on_timeout` when a player runs out of time.
+pub async fn watch_timeouts<GameStateType: GameState+Default, PlayEventT: Serialize+Send>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+) {
+    let mut interval = tokio::time::interval(CHECK_PERIOD);
+    loop {
+        interval.tick().await;
+        for game in universe.timed_games().await {
+            game.check_timeout().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_control(per_turn: Option<u64>, per_game: Option<u64>) -> TimeControl {
+        TimeControl { per_turn, per_game }
+    }
+
+    #[test]
+    fn times_out_once_per_turn() {
+        let player_id = Uuid::new_v4();
+        let start = Instant::now();
+        let mut clocks = Clocks::new(time_control(Some(10), None));
+        assert!(clocks.set_turn(Some(player_id), 1, start));
+
+        assert_eq!(clocks.take_timeout(start + Duration::from_secs(9)), None);
+        assert_eq!(clocks.take_timeout(start + Duration::from_secs(10)), Some((player_id, 1)));
+        assert_eq!(clocks.take_timeout(start + Duration::from_secs(11)), None);
+
+        // A new turn of the same player can time out again
+        let next_turn = start + Duration::from_secs(12);
+        assert!(clocks.set_turn(Some(player_id), 2, next_turn));
+        assert_eq!(clocks.take_timeout(next_turn + Duration::from_secs(10)), Some((player_id, 2)));
+    }
+
+    #[test]
+    fn keeps_the_turn_while_the_turn_number_does_not_change() {
+        let player_id = Uuid::new_v4();
+        let start = Instant::now();
+        let mut clocks = Clocks::new(time_control(Some(10), None));
+        assert!(clocks.set_turn(Some(player_id), 1, start));
+        // Commands which do not end the turn do not give more time
+        assert!(!clocks.set_turn(Some(player_id), 1, start + Duration::from_secs(8)));
+        assert_eq!(clocks.take_timeout(start + Duration::from_secs(10)), Some((player_id, 1)));
+    }
+
+    #[test]
+    fn takes_the_turns_from_the_game_clock() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+        let mut clocks = Clocks::new(time_control(None, Some(60)));
+        clocks.set_turn(Some(first), 1, start);
+        clocks.set_turn(Some(second), 2, start + Duration::from_secs(20));
+
+        let message = clocks.message(vec![first, second].into_iter(), start + Duration::from_secs(25));
+        assert_eq!(message.player_id, Some(second));
+        assert_eq!(message.game_remaining_ms[&first], 40_000);
+        assert_eq!(message.game_remaining_ms[&second], 55_000);
+        assert_eq!(message.turn_remaining_ms, Some(55_000));
+
+        // The first player ran out of time on its own clock
+        clocks.set_turn(Some(first), 3, start + Duration::from_secs(30));
+        assert_eq!(clocks.take_timeout(start + Duration::from_secs(69)), None);
+        assert_eq!(clocks.take_timeout(start + Duration::from_secs(70)), Some((first, 3)));
+    }
+
+    #[test]
+    fn restored_clocks_count_the_time_spent_unloaded() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+        let date = Utc::now();
+        let mut clocks = Clocks::new(time_control(Some(60), Some(300)));
+        clocks.set_turn(Some(first), 1, start);
+        clocks.set_turn(Some(second), 2, start + Duration::from_secs(20));
+        let record = clocks.record(start + Duration::from_secs(30), date);
+        assert_eq!(record.game_remaining_ms[&first], 280_000);
+
+        // Restored 40 seconds later, on another clock
+        let now = Instant::now();
+        let restored = Clocks::new(time_control(Some(60), Some(300)))
+            .restore(&record, now, date + chrono::Duration::seconds(40));
+        let message = restored.message(vec![first, second].into_iter(), now);
+        assert_eq!(message.player_id, Some(second));
+        assert_eq!(message.turn_remaining_ms, Some(10_000));
+        assert_eq!(message.game_remaining_ms[&first], 280_000);
+        assert_eq!(message.game_remaining_ms[&second], 250_000);
+    }
+}
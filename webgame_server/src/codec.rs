@@ -0,0 +1,143 @@
+use serde::{Serialize, de::DeserializeOwned};
+use warp::ws;
+
+use crate::protocol::{ProtocolError, ProtocolErrorKind};
+
+/// Encoding of the commands and messages exchanged on a websocket.
+///
+/// JSON is sent in text frames, the binary codecs in binary frames. Text
+/// frames are always decoded as JSON so that a client can send its `hello`
+/// command before switching to a binary codec.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WireCodec {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl WireCodec {
+    /// Supported codecs, by order of preference of the server.
+    pub const ALL: [WireCodec; 3] = [WireCodec::MessagePack, WireCodec::Cbor, WireCodec::Json];
+
+    /// Name of the codec, as used in the `hello` command.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WireCodec::Json => "json",
+            WireCodec::MessagePack => "msgpack",
+            WireCodec::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|codec| codec.name() == name)
+    }
+
+    /// Websocket subprotocol selecting the codec, ie. `webgame.msgpack`.
+    pub fn subprotocol(&self) -> String {
+        format!("webgame.{}", self.name())
+    }
+
+    /// Chooses the codec from the `Sec-WebSocket-Protocol` header sent by the client.
+    pub fn from_subprotocols(header: &str) -> Option<Self> {
+        let requested: Vec<&str> = header.split(',').map(str::trim).collect();
+        Self::ALL.iter().copied().find(|codec| requested.contains(&codec.subprotocol().as_str()))
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<ws::Message, String> {
+        match self {
+            WireCodec::Json => serde_json::to_string(value)
+                .map(ws::Message::text)
+                .map_err(|err| err.to_string()),
+            WireCodec::MessagePack => rmp_serde::to_vec_named(value)
+                .map(ws::Message::binary)
+                .map_err(|err| err.to_string()),
+            WireCodec::Cbor => serde_cbor::to_vec(value)
+                .map(ws::Message::binary)
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, msg: &ws::Message) -> Result<T, ProtocolError> {
+        let invalid = |err: String| ProtocolError::new(ProtocolErrorKind::InvalidCommand, err);
+        if let Ok(text) = msg.to_str() {
+            return serde_json::from_str(text).map_err(|err| invalid(err.to_string()));
+        }
+        if !msg.is_binary() {
+            return Err(invalid("not a valid text or binary frame".into()));
+        }
+        let bytes = msg.as_bytes();
+        match self {
+            WireCodec::Json => serde_json::from_slice(bytes).map_err(|err| invalid(err.to_string())),
+            WireCodec::MessagePack => rmp_serde::from_slice(bytes).map_err(|err| invalid(err.to_string())),
+            WireCodec::Cbor => serde_cbor::from_slice(bytes).map_err(|err| invalid(err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ChatMessage, Command, CommandEnvelope, Message, MessageEnvelope, SendTextCommand, TimeControl, Variant};
+    use crate::protocol::test_util::{TestOperation, TestPlayer, TestSnapshot};
+    use uuid::Uuid;
+
+    type TestCommand = Command<(), (), TestSnapshot, TestOperation, Variant<u32>>;
+    type TestMessage = Message<TestPlayer, TestSnapshot, TestOperation, ()>;
+
+    fn round_trip<T: Serialize + DeserializeOwned>(codec: WireCodec, value: &T) -> T {
+        let msg = codec.encode(value).unwrap();
+        assert_eq!(msg.is_binary(), codec != WireCodec::Json, "{}", codec.name());
+        codec.decode(&msg).unwrap()
+    }
+
+    #[test]
+    fn decodes_the_encoded_commands() {
+        for codec in WireCodec::ALL {
+            let variant = Variant { parameters: 3, time_control: TimeControl { per_turn: Some(30), per_game: None } };
+            let envelope = CommandEnvelope { req_id: Some(7), command: TestCommand::NewGame(variant) };
+            let decoded = round_trip(codec, &envelope);
+            assert_eq!(decoded.req_id, Some(7), "{}", codec.name());
+            match decoded.command {
+                Command::NewGame(variant) => {
+                    assert_eq!(variant.parameters, 3, "{}", codec.name());
+                    assert_eq!(variant.time_control.per_turn, Some(30), "{}", codec.name());
+                }
+                command => panic!("unexpected command {:?}", command),
+            }
+
+            let envelope = CommandEnvelope { req_id: None, command: TestCommand::SendText(SendTextCommand { text: "hello".into() }) };
+            let decoded = round_trip(codec, &envelope);
+            assert_eq!(decoded.req_id, None, "{}", codec.name());
+            assert!(matches!(decoded.command, Command::SendText(SendTextCommand { ref text }) if text == "hello"), "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn decodes_the_encoded_messages() {
+        for codec in WireCodec::ALL {
+            let chat = ChatMessage { player_id: Uuid::from_u128(1), text: "hello".into() };
+            let envelope = MessageEnvelope { seq: Some(12), message: TestMessage::Chat(chat) };
+            let decoded = round_trip(codec, &envelope);
+            assert_eq!(decoded.seq, Some(12), "{}", codec.name());
+            match decoded.message {
+                Message::Chat(chat) => assert_eq!((chat.player_id, chat.text.as_str()), (Uuid::from_u128(1), "hello")),
+                message => panic!("unexpected message {:?}", message),
+            }
+
+            let snapshot = TestSnapshot { score: 4, players: vec![] };
+            match round_trip(codec, &TestMessage::GameStateSnapshot(snapshot)) {
+                Message::GameStateSnapshot(snapshot) => assert_eq!(snapshot.score, 4, "{}", codec.name()),
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_text_frames_as_json() {
+        let msg = ws::Message::text(r#"{"req_id":2,"cmd":"ping"}"#);
+        let decoded: CommandEnvelope<TestCommand> = WireCodec::MessagePack.decode(&msg).unwrap();
+        assert_eq!(decoded.req_id, Some(2));
+        assert!(matches!(decoded.command, Command::Ping));
+    }
+}
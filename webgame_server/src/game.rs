@@ -1,4 +1,7 @@
+use std::collections::HashSet;
 use std::sync::{Arc, Weak};
+use std::time::Instant;
+use chrono::{DateTime, Utc};
 use futures::executor::block_on;
 use tokio::sync::Mutex;
 use serde::Serialize;
@@ -8,38 +11,34 @@ use std::fmt;
 
 use webgame_protocol::PlayerState;
 use crate::protocol::{
-    GameInfo, GameExtendedInfo, GameState, GameManager, GameEventsListener, // game
-    Message, PlayerDisconnectedMessage, // message
+    GameInfo, GameExtendedInfo, GameOutcome, GameRecord, GameState, GameManager, GameEventsListener, // game
+    Message, GameFinishedMessage, PlayerDisconnectedMessage, RematchAcceptedMessage, // message
     PlayerInfo, // player
-    Variant,
+    TimeControl, Variant,
 };
+use crate::clock::Clocks;
 use crate::universe::Universe;
 
-//trait utilisé dans le store
-pub trait UniverseGame<GameStateType> : Send+Sync {
-    // fn get_state(&self) -> &GameStateType;
-    fn get_state(&self) -> &Arc<Mutex<GameStateType>>;
-    fn get_info(&self) -> GameInfo;
-}
-
-impl<GameStateType: GameState, PlayEventType: Send+Serialize> UniverseGame<GameStateType> for Game<GameStateType, PlayEventType> {
-    // fn get_state(&self) -> &GameStateType {
-    fn get_state(&self) -> &Arc<Mutex<GameStateType>> {
-        self.state_handle()
-    }
-
-    fn get_info(&self) -> GameInfo {
-        let info = self.game_info().clone();
-        info
-    }
-}
-// fin trait utilisé dans le store
-
 pub struct Game<GameStateType: GameState, PlayEventType> {
     pub id: Uuid,
     join_code: String,
     universe: Weak<Universe<GameStateType, PlayEventType>>,
     game_state: Arc<Mutex<GameStateType>>,
+    read_only: bool,
+    // `None` for the games stored before their variant was saved
+    variant: Option<Variant<GameStateType::VariantParameters>>,
+    last_activity: std::sync::Mutex<Instant>,
+    // Last time the game state changed, kept when the game is saved again without changes
+    date_updated: std::sync::Mutex<DateTime<Utc>>,
+    spectators: std::sync::Mutex<HashSet<Uuid>>,
+    finished_at: std::sync::Mutex<Option<DateTime<Utc>>>,
+    // Computed once when the game ends, the players may leave afterwards
+    outcome: std::sync::Mutex<Option<GameOutcome>>,
+    rematch_votes: std::sync::Mutex<HashSet<Uuid>>,
+    // Players who left the finished game, they stay in its state for the record
+    departed: std::sync::Mutex<HashSet<Uuid>>,
+    // `None` when the game has no time control
+    clocks: std::sync::Mutex<Option<Clocks>>,
 }
 
 impl
@@ -49,6 +48,7 @@ fmt::Debug for Game<GameStateType, PlayEventType> {
         f.debug_struct("Game")
          .field("id", &self.id)
          .field("join_code", &self.join_code)
+         .field("read_only", &self.read_only)
          .finish()
     }
 }
@@ -59,12 +59,55 @@ impl<'gs, GameStateType: Default+GameState,
 
     pub fn new(join_code: String, universe: Arc<Universe<GameStateType, PlayEventType>>, variant: Variant<GameStateType::VariantParameters>) -> Game<GameStateType, PlayEventType> {
         let mut game_state = GameStateType::default();
-        game_state.set_variant(variant);
+        let time_control = variant.time_control;
+        game_state.set_variant(variant.clone());
         Game {
             id: Uuid::new_v4(),
             join_code,
             universe: Arc::downgrade(&universe),
             game_state: Arc::new(Mutex::new(game_state)),
+            read_only: false,
+            variant: Some(variant),
+            last_activity: std::sync::Mutex::new(Instant::now()),
+            date_updated: std::sync::Mutex::new(Utc::now()),
+            spectators: std::sync::Mutex::new(HashSet::new()),
+            finished_at: std::sync::Mutex::new(None),
+            outcome: std::sync::Mutex::new(None),
+            rematch_votes: std::sync::Mutex::new(HashSet::new()),
+            departed: std::sync::Mutex::new(HashSet::new()),
+            clocks: std::sync::Mutex::new(make_clocks(time_control)),
+        }
+    }
+
+    /// Rebuilds a game from its stored record, keeping its id and join code.
+    ///
+    /// A read-only game can be viewed by its players but is never modified
+    /// by their commands nor saved in the store. The clocks go on from their
+    /// saved state : the time elapsed while the game was not loaded counts.
+    pub fn from_record(record: GameRecord<GameStateType>, universe: Arc<Universe<GameStateType, PlayEventType>>, read_only: bool) -> Game<GameStateType, PlayEventType> {
+        let time_control = record.variant.as_ref()
+            .map(|variant| variant.time_control)
+            .filter(|_| !read_only)
+            .unwrap_or_default();
+        let clocks = make_clocks(time_control).map(|clocks| match record.clocks {
+            Some(ref saved) => clocks.restore(saved, Instant::now(), Utc::now()),
+            None => clocks,
+        });
+        Game {
+            id: record.info.game_id,
+            join_code: record.info.join_code,
+            universe: Arc::downgrade(&universe),
+            game_state: Arc::new(Mutex::new(record.state)),
+            read_only,
+            variant: record.variant,
+            last_activity: std::sync::Mutex::new(Instant::now()),
+            date_updated: std::sync::Mutex::new(record.date_updated),
+            spectators: std::sync::Mutex::new(HashSet::new()),
+            finished_at: std::sync::Mutex::new(record.finished_at),
+            outcome: std::sync::Mutex::new(record.outcome),
+            rematch_votes: std::sync::Mutex::new(HashSet::new()),
+            departed: std::sync::Mutex::new(HashSet::new()),
+            clocks: std::sync::Mutex::new(clocks),
         }
     }
 
@@ -84,6 +127,49 @@ impl<'gs, GameStateType: Default+GameState,
         &self.join_code
     }
 
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Date the game ended, if it is finished.
+    pub fn finished_at(&self) -> Option<DateTime<Utc>> {
+        *self.finished_at.lock().unwrap()
+    }
+
+    /// Variant the game was created with, needed to start a rematch.
+    pub fn variant(&self) -> Option<&Variant<GameStateType::VariantParameters>> {
+        self.variant.as_ref()
+    }
+
+    /// Records that a player accepts a rematch.
+    ///
+    /// Once more than half of the players accepted, the votes are cleared and
+    /// the players who accepted are returned.
+    pub async fn accept_rematch(&self, player_id: Uuid) -> Option<Vec<Uuid>> {
+        let players = self.seated_players(&*self.game_state.lock().await).len();
+        let (accepted, rematch_players) = {
+            let mut votes = self.rematch_votes.lock().unwrap();
+            votes.insert(player_id);
+            let accepted = votes.len();
+            if accepted * 2 > players {
+                (accepted, Some(std::mem::take(&mut *votes).into_iter().collect()))
+            } else {
+                (accepted, None)
+            }
+        };
+        self.broadcast(&Message::RematchAccepted(RematchAcceptedMessage { player_id, accepted, players })).await;
+        rematch_players
+    }
+
+    /// Last time a message was sent to the players of the game.
+    pub fn last_activity(&self) -> Instant {
+        *self.last_activity.lock().unwrap()
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
     //Used for server diagnostics
     pub async fn game_extended_info(&self) -> GameExtendedInfo {
         let game_state = self.game_state.lock().await;
@@ -94,6 +180,19 @@ impl<'gs, GameStateType: Default+GameState,
         }
     }
 
+    /// Returns a record of the current game state, ready to be stored.
+    pub async fn make_record(&self) -> GameRecord<GameStateType> {
+        let game_state = self.game_state.lock().await.clone();
+        let mut record = GameRecord::create(game_state, self.game_info());
+        record.date_updated = *self.date_updated.lock().unwrap();
+        record.variant = self.variant.clone();
+        record.finished_at = self.finished_at();
+        record.outcome = self.outcome.lock().unwrap().clone();
+        record.clocks = self.clocks.lock().unwrap().as_ref()
+            .map(|clocks| clocks.record(Instant::now(), Utc::now()));
+        record
+    }
+
     pub fn game_info(&self) -> GameInfo {
         GameInfo {
             game_id: self.id,
@@ -102,7 +201,7 @@ impl<'gs, GameStateType: Default+GameState,
     }
 
     pub async fn is_joinable(&self) -> bool {
-        self.game_state.lock().await.is_joinable()
+        !self.read_only && self.game_state.lock().await.is_joinable()
     }
 
     pub fn universe(&self) -> Arc<Universe<GameStateType, PlayEventType>> {
@@ -143,24 +242,55 @@ impl<'gs, GameStateType: Default+GameState,
         connected_ids
     }
 
+    /// Makes a user leave the game.
+    ///
+    /// The players of a finished game are kept in its state, so that its
+    /// record still tells who played it : they only stop receiving its messages.
     pub async fn remove_user(&self, user_id: Uuid) {
-        self.universe().set_user_game_id(user_id, None).await;
-
-        let mut game_state = self.game_state.lock().await;
+        let universe = self.universe();
+        universe.set_user_game_id(user_id, None).await;
+        self.rematch_votes.lock().unwrap().remove(&user_id);
 
-        if game_state.remove_player(user_id) {
-            drop(game_state);
-            self.broadcast(&Message::PlayerDisconnected(PlayerDisconnectedMessage {
-                player_id: user_id,
-            }))
-            .await;
+        if self.finished_at().is_some() {
+            // Nothing changes in the record, the game is not saved again
+            let game_state = self.game_state.lock().await;
+            if game_state.get_players().contains_key(&user_id) && self.departed.lock().unwrap().insert(user_id) {
+                let message = Message::PlayerDisconnected(PlayerDisconnectedMessage { player_id: user_id });
+                self.send_to_table(&universe, &game_state, &message).await;
+            }
+        } else {
+            let mut game_state = self.game_state.lock().await;
+            if game_state.remove_player(user_id) {
+                drop(game_state);
+                self.broadcast(&Message::PlayerDisconnected(PlayerDisconnectedMessage {
+                    player_id: user_id,
+                }))
+                .await;
+            }
         }
 
         if self.is_empty().await {
-            self.universe().remove_game(self.id()).await;
+            universe.remove_game(self.id()).await;
         }
     }
 
+    /// Adds a user watching the game without playing.
+    pub fn add_spectator(&self, user_id: Uuid) {
+        self.spectators.lock().unwrap().insert(user_id);
+    }
+
+    pub fn remove_spectator(&self, user_id: Uuid) {
+        self.spectators.lock().unwrap().remove(&user_id);
+    }
+
+    pub fn is_spectator(&self, user_id: Uuid) -> bool {
+        self.spectators.lock().unwrap().contains(&user_id)
+    }
+
+    pub fn spectators(&self) -> Vec<Uuid> {
+        self.spectators.lock().unwrap().iter().copied().collect()
+    }
+
     pub async fn set_player_not_ready(&self, player_id: Uuid) {
         let mut game_state = self.game_state.lock().await;
         game_state.set_player_not_ready(player_id);
@@ -177,14 +307,38 @@ impl<'gs, GameStateType: Default+GameState,
     }
 
     pub async fn broadcast(&self, message: &Message<GameStateType::GamePlayerState, GameStateType::Snapshot, GameStateType::Operation, PlayEventType>) {
+        self.touch();
         let universe = self.universe();
         {
             let game_state = self.game_state.lock().await;
-            for player_id in game_state.get_players().keys().copied() {
+            for player_id in self.seated_players(&game_state) {
                 universe.send(player_id, message).await;
             }
         }
-        universe.store_state(&self).await;
+        for spectator_id in self.spectators() {
+            universe.send(spectator_id, message).await;
+        }
+        match message {
+            // Chat messages and rematch votes do not change the game state
+            Message::Chat(_) | Message::RematchAccepted(_) => {}
+            Message::PlayerConnected(_) | Message::PlayerDisconnected(_) => self.mark_dirty(&universe, true),
+            _ => self.mark_dirty(&universe, false),
+        }
+    }
+
+    fn mark_dirty(&self, universe: &Universe<GameStateType, PlayEventType>, urgent: bool) {
+        if !self.read_only {
+            *self.date_updated.lock().unwrap() = Utc::now();
+            universe.mark_dirty(self.id, urgent);
+        }
+    }
+
+    /// Sends a message to the spectators only.
+    pub async fn broadcast_to_spectators(&self, message: &Message<GameStateType::GamePlayerState, GameStateType::Snapshot, GameStateType::Operation, PlayEventType>) {
+        let universe = self.universe();
+        for spectator_id in self.spectators() {
+            universe.send(spectator_id, message).await;
+        }
     }
 
     pub async fn send(&self, player_id: Uuid, message: &Message<GameStateType::GamePlayerState, GameStateType::Snapshot, GameStateType::Operation, PlayEventType>) {
@@ -192,35 +346,153 @@ impl<'gs, GameStateType: Default+GameState,
     }
 
     pub async fn broadcast_current_state(&self) {
+        self.touch();
         let game_state = self.game_state.lock().await;
         // self.broadcast_state(game_state).await;
         let universe = self.universe();
-        for player_id in game_state.get_players().keys().copied() {
+        for player_id in self.seated_players(&game_state) {
             let snapshot = game_state.make_snapshot(player_id);
-            universe
-                .send(
-                    player_id,
-                    &Message::GameStateSnapshot(snapshot),
-                )
-                .await;
+            universe.send_snapshot(player_id, snapshot).await;
         }
+        self.send_spectator_snapshots(&universe, &game_state).await;
+        let finished = self.announce_finished(&universe, &game_state).await;
+        self.update_clocks(&universe, &game_state).await;
+        self.mark_dirty(&universe, finished);
     }
 
     pub async fn broadcast_state(&self, game_state: &GameStateType) {
+        self.touch();
         let universe = self.universe();
-        for player_id in game_state.get_players().keys().copied() {
+        for player_id in self.seated_players(game_state) {
             let snapshot = game_state.make_snapshot(player_id);
-            universe
-                .send(
-                    player_id,
-                    &Message::GameStateSnapshot(snapshot),
-                )
-                .await;
+            universe.send_snapshot(player_id, snapshot).await;
+        }
+        self.send_spectator_snapshots(&universe, game_state).await;
+        let finished = self.announce_finished(&universe, game_state).await;
+        self.update_clocks(&universe, game_state).await;
+        self.mark_dirty(&universe, finished);
+    }
+
+    // Records the outcome and sends it to the players and spectators the first
+    // time the game is seen finished, returns true if it was sent.
+    // The game state is already locked : `broadcast` can not be used.
+    async fn announce_finished(&self, universe: &Universe<GameStateType, PlayEventType>, game_state: &GameStateType) -> bool {
+        let finished_at = {
+            let mut finished_at = self.finished_at.lock().unwrap();
+            match (game_state.is_finished(), *finished_at) {
+                (true, None) => *finished_at.insert(Utc::now()),
+                // A game can be started again
+                (false, Some(_)) => {
+                    *finished_at = None;
+                    *self.outcome.lock().unwrap() = None;
+                    return false;
+                }
+                _ => return false,
+            }
+        };
+        let outcome = game_state.outcome();
+        *self.outcome.lock().unwrap() = Some(outcome.clone());
+        let message = Message::GameFinished(GameFinishedMessage {
+            finished_at,
+            outcome,
+        });
+        log::info!("game {} finished", self.id);
+        self.send_to_table(universe, game_state, &message).await;
+        true
+    }
+
+    // Sends a message to the players and spectators while the game state is locked
+    async fn send_to_table(&self, universe: &Universe<GameStateType, PlayEventType>, game_state: &GameStateType, message: &Message<GameStateType::GamePlayerState, GameStateType::Snapshot, GameStateType::Operation, PlayEventType>) {
+        for player_id in self.seated_players(game_state) {
+            universe.send(player_id, message).await;
+        }
+        for spectator_id in self.spectators() {
+            universe.send(spectator_id, message).await;
+        }
+    }
+
+    // Players who did not leave the game
+    fn seated_players(&self, game_state: &GameStateType) -> Vec<Uuid> {
+        let departed = self.departed.lock().unwrap();
+        game_state.get_players().keys().copied()
+            .filter(|player_id| !departed.contains(player_id))
+            .collect()
+    }
+
+    pub fn has_clocks(&self) -> bool {
+        self.clocks.lock().unwrap().is_some()
+    }
+
+    // Starts the clock of the player who must play, and sends the clocks when the turn changed
+    async fn update_clocks(&self, universe: &Universe<GameStateType, PlayEventType>, game_state: &GameStateType) {
+        let message = {
+            let mut clocks = self.clocks.lock().unwrap();
+            let clocks = match clocks.as_mut() {
+                Some(clocks) => clocks,
+                None => return,
+            };
+            let now = Instant::now();
+            let player_id = if game_state.is_finished() { None } else { game_state.current_player() };
+            if !clocks.set_turn(player_id, game_state.turn_number(), now) {
+                return;
+            }
+            clocks.message(game_state.get_players().keys().copied(), now)
+        };
+        self.send_to_table(universe, game_state, &Message::Clock(message)).await;
+    }
+
+    /// Sends the state of the clocks to a player or a spectator.
+    pub async fn send_clocks(&self, user_id: Uuid) {
+        let game_state = self.game_state.lock().await;
+        let message = match self.clocks.lock().unwrap().as_ref() {
+            Some(clocks) => clocks.message(game_state.get_players().keys().copied(), Instant::now()),
+            None => return,
+        };
+        self.universe().send(user_id, &Message::Clock(message)).await;
+    }
+
+    /// Lets the game play for the current player if it ran out of time.
+    pub async fn check_timeout(&self) {
+        let timed_out = self.clocks.lock().unwrap().as_mut()
+            .and_then(|clocks| clocks.take_timeout(Instant::now()));
+        let (player_id, turn_number) = match timed_out {
+            Some(timed_out) => timed_out,
+            None => return,
+        };
+        let mut game_state = self.game_state.lock().await;
+        // The player may have played in the meantime
+        if game_state.current_player() != Some(player_id) || game_state.turn_number() != turn_number {
+            return;
         }
+        log::info!("player {:?} ran out of time in game {}", player_id, self.id);
+        game_state.on_timeout(player_id);
+        self.broadcast_state(&game_state).await;
+    }
+
+    async fn send_spectator_snapshots(&self, universe: &Universe<GameStateType, PlayEventType>, game_state: &GameStateType) {
+        for spectator_id in self.spectators() {
+            universe.send_snapshot(spectator_id, game_state.make_spectator_snapshot()).await;
+        }
+    }
+
+    /// Sends the full state of the game to a player or a spectator.
+    pub async fn send_full_snapshot(&self, player_id: Uuid) {
+        let universe = self.universe();
+        let snapshot = if self.is_spectator(player_id) {
+            self.game_state.lock().await.make_spectator_snapshot()
+        } else {
+            self.game_state.lock().await.make_snapshot(player_id)
+        };
+        universe.reset_snapshot(player_id).await;
+        universe.send_snapshot(player_id, snapshot).await;
+        self.send_clocks(player_id).await;
     }
 
     pub async fn get_player(&self, player_id: &Uuid) -> Option<PlayerInfo> {
         let mut player: Option<PlayerInfo> = None;
+        if self.departed.lock().unwrap().contains(player_id) {
+            return player;
+        }
 
         if let Some(state) = self.game_state.lock().await.get_players().get(player_id) {
             player = Some(state.clone().player());
@@ -229,7 +501,15 @@ impl<'gs, GameStateType: Default+GameState,
     }
 
     pub async fn is_empty(&self) -> bool {
-        self.game_state.lock().await.get_players().is_empty()
+        self.seated_players(&*self.game_state.lock().await).is_empty()
     }
 
 }
+
+fn make_clocks(time_control: TimeControl) -> Option<Clocks> {
+    if time_control.is_unlimited() {
+        None
+    } else {
+        Some(Clocks::new(time_control))
+    }
+}
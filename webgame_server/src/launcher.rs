@@ -1,20 +1,17 @@
-use clap::{Arg, App};
+use clap::{Arg, App, SubCommand};
 use serde::{Serialize, de::DeserializeOwned};
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::path::Path;
 
 use std::thread;
-use futures::executor::block_on;
 use std::time::Duration;
-use chrono::{Utc, DateTime};
-use std::path::Path;
-use std::fs::File;
-use std::fs;
 
-use webgame_protocol::{GameState, GameRecord};
+use webgame_protocol::GameState;
+use crate::admin::AdminSettings;
+use crate::archive::{ArchiveConfig, ArchiveFormat};
 use crate::server;
-use crate::store::GameStore;
-use crate::store_sled::SledStore;
+use crate::session::Sessions;
+use crate::store;
 
 extern crate pretty_env_logger;
 
@@ -64,7 +61,7 @@ pub async fn launch<
         .arg(Arg::with_name("archive_delay")
              .long("archive-delay")
              .value_name("ARCHIVEDELAY")
-             .help("Retention period in minutes after wich the game is archived")
+             .help("Retention period in minutes after wich a finished or abandoned game is archived")
              // .help("Retention period in hours after wich the game is archived")
              .takes_value(true))
         .arg(Arg::with_name("archive_check")
@@ -72,6 +69,44 @@ pub async fn launch<
              .value_name("ARCHIVECHECK")
              .help("Archivage check period in minutes")
              .takes_value(true))
+        .arg(Arg::with_name("archive_format")
+             .long("archive-format")
+             .value_name("ARCHIVEFORMAT")
+             .help("Format of the archives: json (one file per game), gzip (one compressed file per game), daily (one file per day)")
+             .takes_value(true))
+        .arg(Arg::with_name("archive_retention")
+             .long("archive-retention")
+             .value_name("ARCHIVERETENTION")
+             .help("Number of days the archives are kept, they are never deleted if not set")
+             .takes_value(true))
+        .arg(Arg::with_name("persist_interval")
+             .long("persist-interval")
+             .value_name("PERSISTINTERVAL")
+             .help("Period in seconds between two saves of the modified games")
+             .takes_value(true))
+        .arg(Arg::with_name("evict_after")
+             .long("evict-after")
+             .value_name("EVICTAFTER")
+             .help("Minutes without activity after which a game is dropped from memory until a player comes back, 0 to keep all games in memory (default 60)")
+             .takes_value(true))
+        .arg(Arg::with_name("session_secret")
+             .long("session-secret")
+             .value_name("SESSIONSECRET")
+             .help("Secret key signing the session tokens, if not set a random one is kept in the DBPATH.session-secret file next to the database")
+             .takes_value(true))
+        .arg(Arg::with_name("session_ttl")
+             .long("session-ttl")
+             .value_name("SESSIONTTL")
+             .help("Validity period in hours of the session tokens (default 168)")
+             .takes_value(true))
+        .arg(Arg::with_name("admin_token")
+             .long("admin-token")
+             .value_name("ADMINTOKEN")
+             .help("Token giving the admin role with the admin_login command, needed to see the server status, nobody can be admin if not set")
+             .takes_value(true))
+        .arg(Arg::with_name("debug")
+             .long("debug")
+             .help("Enables the debug_ui and debug_game commands for the admins"))
         .arg(Arg::with_name("address")
              .short("a")
              .long("ip address")
@@ -88,8 +123,16 @@ pub async fn launch<
              .short("u")
              .long("db-uri")
              .value_name("DBURI")
-             .help("Uri of the database storing game states")
+             .help("Uri of the database storing game states (sled://PATH[?encoding=bincode|json|cbor], sqlite://PATH, json://DIRECTORY, memory://, print://)")
              .takes_value(true))
+        .subcommand(SubCommand::with_name("reencode")
+             .about("Copies the games of the database into another one, the server must be stopped")
+             .arg(Arg::with_name("target")
+                  .long("to")
+                  .value_name("TARGETURI")
+                  .help("Uri of the new database, ie. sled://PATH?encoding=json")
+                  .required(true)
+                  .takes_value(true)))
         ;
     let matches = app.get_matches();
 
@@ -106,45 +149,80 @@ pub async fn launch<
 
     let db_uri = matches.value_of("databaseuri").unwrap_or("webgame_db");
     let archives_dir = matches.value_of("archives").unwrap_or("webgame_archives");
-    let cleaner_archive_after = matches.value_of("archive_delay").and_then(|val| val.parse::<i64>().ok()).unwrap_or(24);
-    let cleaner_check_interval = matches.value_of("archive_check").and_then(|val| val.parse::<u64>().ok()).unwrap_or(120);
-    let store = Arc::new(SledStore::new(&db_uri));
-
-    let str_socket = format!("{}:{}", str_ip, str_port);
-    if let Ok(socket) = str_socket.parse() {
-
-        // XXX Cleaning task : should be defined in another module ?
-        let cleaner_store = Arc::clone(&store);
-        let cleaner_archives = String::from(archives_dir);
+    let archive_config = ArchiveConfig {
+        archive_after: chrono::Duration::minutes(matches.value_of("archive_delay").and_then(|val| val.parse::<i64>().ok()).unwrap_or(24)),
+        check_interval: Duration::from_secs(60 * matches.value_of("archive_check").and_then(|val| val.parse::<u64>().ok()).unwrap_or(120)),
+        retention: matches.value_of("archive_retention").and_then(|val| val.parse::<i64>().ok()).map(chrono::Duration::days),
+    };
+    let archive_format = match matches.value_of("archive_format").unwrap_or("json").parse::<ArchiveFormat>() {
+        Ok(format) => format,
+        Err(err) => {
+            error!("{}", err);
+            return;
+        }
+    };
+    let persist_interval = matches.value_of("persist_interval").and_then(|val| val.parse::<u64>().ok()).unwrap_or(5);
+    let evict_after = matches.value_of("evict_after").and_then(|val| val.parse::<u64>().ok()).unwrap_or(60);
+    let evict_after = Some(Duration::from_secs(60 * evict_after)).filter(|duration| !duration.is_zero());
+    let session_ttl = chrono::Duration::hours(matches.value_of("session_ttl").and_then(|val| val.parse::<i64>().ok()).unwrap_or(168));
+    let admin = AdminSettings {
+        token: matches.value_of("admin_token").map(String::from),
+        debug: matches.is_present("debug"),
+    };
+    if admin.debug {
+        warn!("Debug commands enabled, admins can modify any game");
+    }
+    let store = match store::open_store::<GameStateType>(db_uri) {
+        Ok(store) => store,
+        Err(err) => {
+            error!("Could not open store {}: {}", db_uri, err);
+            return;
+        }
+    };
 
-        if !Path::new(&cleaner_archives).exists(){
-            println!("Try to create archives directory {:?}", cleaner_archives);
-            fs::create_dir(&cleaner_archives).unwrap();
+    if let Some(reencode) = matches.subcommand_matches("reencode") {
+        let target_uri = reencode.value_of("target").unwrap();
+        let target = match store::open_store::<GameStateType>(target_uri) {
+            Ok(target) => target,
+            Err(err) => {
+                error!("Could not open store {}: {}", target_uri, err);
+                return;
+            }
+        };
+        match store::copy_records(&store, &target).await {
+            Ok((copied, failed)) => println!("{} games copied to {}, {} could not be read", copied, target_uri, failed),
+            Err(err) => error!("Could not copy games to {}: {}", target_uri, err),
         }
+        return;
+    }
 
-        thread::spawn(move || {
-            loop {
-                let now = Utc::now().time();
-                let fgames: Vec<GameRecord<GameStateType>> = cleaner_store.data().iter()
-                    .map(|res| res.map(|game| game.1))
-                    .filter_map(Result::ok)
-                    // .filter(|d| (now - d.date_updated.time()).num_hours() > cleaner_archive_after )
-                    .filter(|d| (now - d.date_updated.time()).num_minutes() > cleaner_archive_after )
-                    .collect();
-                debug!("{} games to archive", fgames.len());
-                for g in fgames {
-                    debug!("trying to save {}", &g.info.game_id);
-                    let filename = format!("{}/{}.json", cleaner_archives, &g.info.game_id);
-                    if let Ok(_ok) = serde_json::to_writer(&File::create(&filename).unwrap(), &g) {
-                        debug!("stored {}", &filename);
-                        if block_on(cleaner_store.delete(g.info.game_id)) {
-                            debug!("and deleted.. ");
-                        }
-                    }
+    let sessions = match (matches.value_of("session_secret"), store::store_path(db_uri)) {
+        (Some(secret), _) => Sessions::new(secret.as_bytes(), session_ttl),
+        // Kept next to the database, so that the players can come back after a restart
+        (None, Some(db_path)) => {
+            let secret_path = format!("{}.session-secret", db_path);
+            match Sessions::with_secret_file(Path::new(&secret_path), session_ttl) {
+                Ok(sessions) => sessions,
+                Err(err) => {
+                    error!("Could not read or create the session secret {}: {}", secret_path, err);
+                    return;
                 }
-                thread::sleep(Duration::from_secs(60 * cleaner_check_interval));
             }
-        });
+        }
+        // The games are lost on restart anyway
+        (None, None) => Sessions::with_random_secret(session_ttl),
+    };
+
+    let str_socket = format!("{}:{}", str_ip, str_port);
+    if let Ok(socket) = str_socket.parse() {
+
+        let archive_sink = match archive_format.sink::<GameStateType>(archives_dir) {
+            Ok(sink) => sink,
+            Err(err) => {
+                error!("Could not create archives directory {}: {}", archives_dir, err);
+                return;
+            }
+        };
 
         let bots_socket = String::from(str_bots_socket);
         let wsocket = format!("ws://{}", str_socket);
@@ -155,9 +233,15 @@ pub async fn launch<
         server::serve(
             String::from(public_dir),
             store,
+            Some(archive_sink),
+            archive_config,
+            sessions,
+            admin,
             String::from(str_bots_socket),
             // bots_stream,
             socket,
+            Duration::from_secs(persist_interval),
+            evict_after,
             on_gameplay,
             on_setplayerrole,
             ).await;
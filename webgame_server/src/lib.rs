@@ -1,11 +1,21 @@
+pub mod admin;
 pub mod launcher;
 pub mod universe;
 pub mod game;
+pub mod archive;
+pub mod codec;
+mod clock;
+mod outbox;
+mod persistence;
 mod server;
+pub mod session;
 mod utils;
-mod store;
+pub mod store;
+mod store_jsondir;
+mod store_memory;
 mod store_print;
 mod store_sled;
+mod store_sqlite;
 
 pub(crate) use webgame_protocol as protocol;
 
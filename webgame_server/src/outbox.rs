@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+use warp::ws;
+
+use crate::codec::WireCodec;
+use crate::protocol::MessageEnvelope;
+
+/// Number of messages kept for each user
+pub const OUTBOX_CAPACITY: usize = 256;
+
+/// Numbers the messages sent to a user and keeps the last ones which can
+/// be replayed.
+///
+/// The outbox is kept while the user is disconnected, so that a client
+/// reconnecting can get the messages it missed. The messages are kept as the
+/// frames sent with each codec, a replayed message is the same as the
+/// original one whatever codec the client chooses when it reconnects.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    next_seq: u64,
+    messages: VecDeque<(u64, Vec<(WireCodec, ws::Message)>)>,
+}
+
+impl Outbox {
+    /// Gives the next sequence number to a message, and keeps it when `replayable`.
+    pub fn record<M: Serialize>(&mut self, message: &M, replayable: bool) -> u64 {
+        self.next_seq += 1;
+        let seq = self.next_seq;
+        if replayable {
+            let envelope = MessageEnvelope { seq: Some(seq), message };
+            let frames = WireCodec::ALL.iter()
+                .filter_map(|codec| match codec.encode(&envelope) {
+                    Ok(frame) => Some((*codec, frame)),
+                    Err(err) => {
+                        log::error!("could not keep message {} for {}: {}", seq, codec.name(), err);
+                        None
+                    }
+                })
+                .collect();
+            if self.messages.len() == OUTBOX_CAPACITY {
+                self.messages.pop_front();
+            }
+            self.messages.push_back((seq, frames));
+        }
+        seq
+    }
+
+    /// Returns the frames encoded with `codec` of the kept messages sent after `last_seq`.
+    pub fn since(&self, last_seq: u64, codec: WireCodec) -> impl Iterator<Item=(u64, &ws::Message)> {
+        self.messages.iter()
+            .filter(move |(seq, _)| *seq > last_seq)
+            .filter_map(move |(seq, frames)| {
+                frames.iter().find(|(frame_codec, _)| *frame_codec == codec).map(|(_, frame)| (*seq, frame))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn seqs(outbox: &Outbox, last_seq: u64) -> Vec<u64> {
+        outbox.since(last_seq, WireCodec::Json).map(|(seq, _)| seq).collect()
+    }
+
+    #[test]
+    fn returns_the_replayable_messages_after_a_seq() {
+        let mut outbox = Outbox::default();
+        assert_eq!(outbox.record(&json!({"text": "play"}), true), 1);
+        assert_eq!(outbox.record(&json!({"text": "snapshot"}), false), 2);
+        assert_eq!(outbox.record(&json!({"text": "chat"}), true), 3);
+
+        assert_eq!(seqs(&outbox, 0), vec![1, 3]);
+        assert_eq!(seqs(&outbox, 1), vec![3]);
+        assert!(seqs(&outbox, 3).is_empty());
+        let (_, frame) = outbox.since(2, WireCodec::Json).next().unwrap();
+        assert_eq!(frame.to_str().unwrap(), r#"{"seq":3,"text":"chat"}"#);
+    }
+
+    #[test]
+    fn keeps_the_last_messages() {
+        let mut outbox = Outbox::default();
+        for n in 0..OUTBOX_CAPACITY + 10 {
+            outbox.record(&json!({ "n": n }), true);
+        }
+        let kept = seqs(&outbox, 0);
+        assert_eq!(kept.len(), OUTBOX_CAPACITY);
+        assert_eq!(kept[0], 11);
+        assert_eq!(*kept.last().unwrap(), (OUTBOX_CAPACITY + 10) as u64);
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::protocol::GameState;
+use crate::universe::Universe;
+
+/// Saves the modified games in the background.
+///
+/// Changes are coalesced : a game modified several times between two
+/// flushes is only saved once. Flushes happen every `interval`, or sooner
+/// when a game is marked dirty with the `urgent` flag.
+pub async fn write_behind<GameStateType: GameState, PlayEventT: Serialize+Send>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    interval: Duration,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = universe.flush_requested() => {}
+        }
+        let saved = universe.flush_dirty_games().await;
+        if saved > 0 {
+            log::debug!("{} games saved", saved);
+        }
+    }
+}
+
+/// Drops from memory the games without activity for `idle_after`.
+///
+/// Evicted games are reloaded from the store when a player comes back,
+/// see `Universe::load_game`.
+pub async fn evict_idle<GameStateType: GameState, PlayEventT: Serialize+Send>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    idle_after: Duration,
+) {
+    let period = (idle_after / 4).max(Duration::from_secs(1));
+    loop {
+        tokio::time::sleep(period).await;
+        let evicted = universe.evict_idle_games(idle_after).await;
+        if evicted > 0 {
+            log::info!("{} idle games evicted", evicted);
+        }
+    }
+}
@@ -1,18 +1,21 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::future::Future;
 use std::sync::Arc;
 
 use std::pin::Pin;
 
 use std::net::SocketAddr;
 use std::fmt::Debug;
+use std::time::Duration;
 
-use serde::{Serialize, de::DeserializeOwned};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use futures::{FutureExt, StreamExt};
 use hyper::{service::make_service_fn, Server};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
-use warp::{ws, Filter};
+use warp::{ws, Filter, Reply};
 
 //For bots socket
 use std::os::unix::net::UnixStream;
@@ -21,13 +24,35 @@ use std::sync::Mutex;
 //For keep alive ping pong
 // use std::time::Duration;
 use crate::protocol::{
-    AuthenticateCommand, ChatMessage, ServerStatus, Command, JoinGameCommand, Message, ProtocolError,
+    AckMessage, AuthenticateCommand, CommandEnvelope, HelloCommand, WelcomeMessage, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, ChatMessage, SpectatorChatMessage, ServerStatus, Command, JoinGameCommand, ResumeGameCommand, ReplayCommand, Message, ProtocolError,
     ProtocolErrorKind, SendTextCommand, Variant,
-    DebugUiCommand, DebugGameCommand,
+    AdminLoginCommand, DebugUiCommand, DebugGameCommand, ImportArchiveCommand,
     GameState,
 };
-use crate::universe::Universe;
-use crate::store_sled::SledStore;
+use crate::admin::AdminSettings;
+use crate::archive::{self, ArchiveConfig, ArchiveSink};
+use crate::clock;
+use crate::codec::WireCodec;
+use crate::universe::{Universe, DELTA_CAPABILITY, REPLAY_CAPABILITY};
+use crate::persistence;
+use crate::session::Sessions;
+use crate::store::SharedGameStore;
+
+// Commands accepted by the server for a given game
+type GameCommand<GamePlayCommand, SetPlayerRoleCommand, GameStateType> = Command<
+    GamePlayCommand,
+    SetPlayerRoleCommand,
+    <GameStateType as GameState>::Snapshot,
+    <GameStateType as GameState>::Operation,
+    Variant<<GameStateType as GameState>::VariantParameters>,
+>;
+
+/// Optional features advertised in the welcome message.
+///
+/// `delta` and `replay` change what is sent to the client, they are only
+/// enabled when requested with the `hello` command. The other ones tell the
+/// client which commands it can offer to the player.
+pub const CAPABILITIES: &[&str] = &[DELTA_CAPABILITY, REPLAY_CAPABILITY, "spectate", "rematch"];
 
 // see https://users.rust-lang.org/t/how-to-store-async-function-pointer/38343/2
 pub type GamePlayHandler<GamePlayCommand, GameStateType, PlayEventT> = fn( Arc<Universe<GameStateType, PlayEventT>>, Uuid, GamePlayCommand ) 
@@ -45,6 +70,8 @@ async fn on_websocket_connect<
     GameStateType: GameState+Default, PlayEventT:Send+Serialize>(
     universe: Arc<Universe<GameStateType, PlayEventT>>,
     guid_uuid: String,
+    token: Option<String>,
+    codec: WireCodec,
     ws: ws::WebSocket,
     on_gameplay: GamePlayHandler<GamePlayCommand, GameStateType, PlayEventT>,
     on_setplayerrole: SetPlayerRoleHandler<SetPlayerRoleCommand, GameStateType, PlayEventT>,
@@ -75,19 +102,22 @@ async fn on_websocket_connect<
     } else {
         "none"
     };
-    let (user, gameuid) = universe.add_user(tx, guid.into(), uuid.into()).await;
+    let (user, gameuid) = universe.add_user(tx, guid.into(), uuid.into(), token).await;
     log::info!("user {:?} connected", user.id);
+    universe.set_user_codec(user.id, codec).await;
     if universe.user_is_authenticated(user.id).await {
         universe
             .send(user.id, &Message::Authenticated(user.clone().into()))
             .await;
+        send_session_token(&universe, user.id).await;
     }
     if let Some(game_id) = gameuid {
-        if let Some(game) = universe.get_game(game_id).await {
+        if let Some(game) = universe.load_game(game_id).await {
             universe
                 .send(user.id, &Message::GameJoined(game.game_info()))
                 .await;
             game.broadcast_current_state().await;
+            game.send_clocks(user.id).await;
         }
     }
 
@@ -108,22 +138,24 @@ async fn on_websocket_connect<
     //end keep alive
 
 
+    // The user id changes when a returning player resumes a game
+    let mut user_id = user.id;
     while let Some(result) = user_ws_rx.next().await {
         match result {
             Ok(msg) => {
                 log::debug!("Got message from websocket: {:?}", &msg);
-                if let Err(err) = on_user_message(universe.clone(), user.id, msg, on_gameplay, on_setplayerrole).await {
-                    universe.send(user.id, &Message::Error(err)).await;
+                if let Err(err) = on_user_message(universe.clone(), &mut user_id, msg, on_gameplay, on_setplayerrole).await {
+                    universe.send(user_id, &Message::Error(err)).await;
                 }
             }
             Err(e) => {
-                log::error!("websocket error(uid={}): {}", user.id, e);
+                log::error!("websocket error(uid={}): {}", user_id, e);
                 break;
             }
         }
     }
 
-    on_user_disconnected(universe, user.id).await;
+    on_user_disconnected(universe, user_id).await;
 }
 
 async fn on_user_disconnected<GameStateType:GameState+Default, PlayEventT:Send+Serialize>(universe: Arc<Universe<GameStateType, PlayEventT>>, user_id: Uuid) {
@@ -147,52 +179,89 @@ async fn on_user_message<
     >
        (
     universe: Arc<Universe<GameStateType, PlayEventT>>,
-    user_id: Uuid,
+    current_user_id: &mut Uuid,
     msg: ws::Message,
     on_gameplay: GamePlayHandler<GamePlayCommand, GameStateType, PlayEventT>,
     on_setplayerrole: SetPlayerRoleHandler<SetPlayerRoleCommand, GameStateType, PlayEventT>,
 ) -> Result<(), ProtocolError> 
        where GameStateType::VariantParameters: DeserializeOwned + std::fmt::Debug
 {
+    let user_id = *current_user_id;
     if msg.is_ping() {
         // XXX A warp ping. where does it come from ? Whatever, we manage it like our custom pings
         log::error!("received a warp ping: {:?}", msg);
         return on_ping(universe, user_id).await;
     }
 
-    let req_json = match msg.to_str() {
-        Ok(text) => text,
-        Err(()) => {
-            return Err(ProtocolError::new(
-                ProtocolErrorKind::InvalidCommand,
-                "not a valid text frame",
-            ))
-        }
-    };
-
-    let cmd: Command<GamePlayCommand, SetPlayerRoleCommand, GameStateType::Snapshot, GameStateType::Operation, Variant<GameStateType::VariantParameters>> = match serde_json::from_str(&req_json) {
+    let codec = universe.user_codec(user_id).await;
+    let envelope: CommandEnvelope<GameCommand<GamePlayCommand, SetPlayerRoleCommand, GameStateType>> = match codec.decode(&msg) {
         Ok(req) => req,
         Err(err) => {
-            log::debug!("error parsing json {}", err);
-            return Err(ProtocolError::new(
-                ProtocolErrorKind::InvalidCommand,
-                err.to_string(),
-            ));
+            log::debug!("error decoding command {}", err.message());
+            // The request id is echoed even if the command is invalid
+            let req_id = codec.decode::<RequestId>(&msg).ok().and_then(|request| request.req_id);
+            return Err(err.with_req_id(req_id));
         }
     };
 
+    let req_id = envelope.req_id;
+    match on_command(universe.clone(), current_user_id, envelope.command, on_gameplay, on_setplayerrole).await {
+        Ok(()) => {
+            if let Some(req_id) = req_id {
+                universe.send(*current_user_id, &Message::Ack(AckMessage { req_id })).await;
+            }
+            Ok(())
+        }
+        Err(err) => Err(err.with_req_id(req_id)),
+    }
+}
+
+#[derive(Deserialize)]
+struct RequestId {
+    req_id: Option<u64>,
+}
+
+async fn on_command<
+    GamePlayCommand: DeserializeOwned + std::fmt::Debug,
+    SetPlayerRoleCommand: DeserializeOwned + std::fmt::Debug,
+    GameStateType:GameState+Default,
+    PlayEventT:Send+Serialize,
+    >
+       (
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    current_user_id: &mut Uuid,
+    cmd: GameCommand<GamePlayCommand, SetPlayerRoleCommand, GameStateType>,
+    on_gameplay: GamePlayHandler<GamePlayCommand, GameStateType, PlayEventT>,
+    on_setplayerrole: SetPlayerRoleHandler<SetPlayerRoleCommand, GameStateType, PlayEventT>,
+) -> Result<(), ProtocolError>
+       where GameStateType::VariantParameters: DeserializeOwned + std::fmt::Debug
+{
+    let user_id = *current_user_id;
     log::debug!("command: {:?}", &cmd);
 
+    // The administration commands depend on the role of the connection, not on the user authentication
+    let cmd = match cmd {
+        Command::AdminLogin(data) => return on_admin_login(universe, user_id, data).await,
+        Command::ShowServerStatus => return as_admin(&universe, user_id, "show_server_status".into(), false,
+            on_server_status(universe.clone(), user_id)).await,
+        Command::ShowServerGames => return as_admin(&universe, user_id, "show_server_games".into(), false,
+            on_server_games(universe.clone(), user_id)).await,
+        Command::ShowUuid => return as_admin(&universe, user_id, "show_uuid".into(), false,
+            on_show_uuid(universe.clone(), user_id)).await,
+        Command::ImportArchive(data) => return as_admin(&universe, user_id, format!("import_archive {} {:?}", data.game_id, data.mode), false,
+            on_import_archive(universe.clone(), user_id, data)).await,
+        Command::DebugUi(data) => return as_admin(&universe, user_id, format!("debug_ui {}", data.player_id), true,
+            on_debug_ui(universe.clone(), data)).await,
+        Command::DebugGame(data) => return as_admin(&universe, user_id, format!("debug_game {} {:?}", data.game_id, data.operation), true,
+            on_debug_game(universe.clone(), data)).await,
+        cmd => cmd,
+    };
+
     if !universe.user_is_authenticated(user_id).await {
         match cmd {
+            Command::Hello(data) => on_hello(universe, user_id, data).await,
             Command::Authenticate(data) => on_player_authenticate(universe, user_id, data).await,
-
-            //For debug purposes only
-            Command::ShowServerStatus => on_server_status(universe, user_id).await,
-            Command::ShowServerGames => on_server_games(universe, user_id).await,
-            Command::ShowUuid => on_show_uuid(universe, user_id).await,
-            Command::DebugUi(data) => on_debug_ui(universe, data).await,
-            Command::DebugGame(data) => on_debug_game(universe, data).await,
+            Command::ResumeGame(cmd) => on_resume_game(universe, current_user_id, cmd).await,
 
             _ => Err(ProtocolError::new(
                 ProtocolErrorKind::NotAuthenticated,
@@ -200,13 +269,30 @@ async fn on_user_message<
             )),
         }
     } else {
+        if changes_game(&cmd) {
+            if let Some(game) = universe.get_user_game(user_id).await {
+                if game.is_read_only() {
+                    return Err(ProtocolError::new(
+                        ProtocolErrorKind::BadState,
+                        "game is read-only",
+                    ));
+                }
+            }
+        }
+
         match cmd {
             Command::Ping => on_ping(universe, user_id).await,
+            Command::Hello(data) => on_hello(universe, user_id, data).await,
 
             Command::NewGame(variant) => on_new_game(universe, user_id, variant).await,
             Command::JoinGame(cmd) => on_join_game(universe, user_id, cmd).await,
+            Command::Spectate(cmd) => on_spectate(universe, user_id, cmd).await,
+            Command::ResumeGame(cmd) => on_resume_game(universe, current_user_id, cmd).await,
+            Command::RequestResync => on_request_resync(universe, user_id).await,
+            Command::Replay(cmd) => on_replay(universe, user_id, cmd).await,
             Command::MarkReady => on_player_mark_ready(universe, user_id).await,
             Command::LeaveGame => on_leave_game(universe, user_id).await,
+            Command::Rematch => on_rematch(universe, user_id).await,
             Command::InviteBot => on_invite_bot(universe, user_id).await,
 
             Command::Continue => on_player_continue(universe, user_id).await,
@@ -214,29 +300,46 @@ async fn on_user_message<
 
             Command::SetPlayerRole(cmd) => on_setplayerrole(universe, user_id, cmd).await,
             Command::GamePlay(cmd) => on_gameplay(universe, user_id, cmd).await,
-            //For debug purposes only
-            Command::ShowUuid => on_show_uuid(universe, user_id).await,
-            Command::DebugUi(data) => on_debug_ui(universe, data).await,
-            Command::DebugGame(data) => on_debug_game(universe, data).await,
-            Command::ShowServerStatus => on_server_status(universe, user_id).await,
-            Command::ShowServerGames => on_server_games(universe, user_id).await,
 
             // this should not happen here.
             Command::Authenticate(..) => Err(ProtocolError::new(
                 ProtocolErrorKind::AlreadyAuthenticated,
                 "cannot authenticate twice",
             )),
+            // handled above
+            Command::AdminLogin(..)
+            | Command::ShowUuid
+            | Command::DebugUi(..)
+            | Command::DebugGame(..)
+            | Command::ShowServerStatus
+            | Command::ShowServerGames
+            | Command::ImportArchive(..) => unreachable!(),
         }
     }
 }
 
 
+// Commands which can modify the state of the game the user is in
+fn changes_game<GamePlayCommand, SetPlayerRoleCommand, GameStateSnapshot, DebugOperation, VariantCommand>(
+    cmd: &Command<GamePlayCommand, SetPlayerRoleCommand, GameStateSnapshot, DebugOperation, VariantCommand>,
+) -> bool {
+    matches!(cmd,
+        Command::MarkReady
+        | Command::Rematch
+        | Command::InviteBot
+        | Command::Continue
+        | Command::GamePlay(_)
+        | Command::SetPlayerRole(_)
+    )
+}
+
 async fn on_new_game<'de, GameStateType, PlayEventT>(universe: Arc<Universe<GameStateType, PlayEventT>>, user_id: Uuid, variant: Variant<GameStateType::VariantParameters>) -> Result<(), ProtocolError> 
 where 
     GameStateType:      GameState+Default,
     PlayEventT:         Send+Serialize
 {
     universe.remove_user_from_game(user_id).await;
+    universe.stop_spectating(user_id).await;
     let game = universe.new_game(variant).await;
     game.add_player(user_id).await;
     universe
@@ -252,6 +355,7 @@ async fn on_join_game<'de, GameStateType:GameState+Default, PlayEventT:Send+Seri
     cmd: JoinGameCommand,
 ) -> Result<(), ProtocolError> {
     let game = universe.join_game(user_id, cmd.join_code).await?;
+    universe.stop_spectating(user_id).await;
     universe
         .send(user_id, &Message::GameJoined(game.game_info()))
         .await;
@@ -259,6 +363,86 @@ async fn on_join_game<'de, GameStateType:GameState+Default, PlayEventT:Send+Seri
     Ok(())
 }
 
+async fn on_spectate<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    user_id: Uuid,
+    cmd: JoinGameCommand,
+) -> Result<(), ProtocolError> {
+    if universe.get_user_game(user_id).await.is_some() {
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::BadState,
+            "cannot watch a game while playing",
+        ));
+    }
+    let game = universe.spectate_game(user_id, cmd.join_code).await?;
+    log::info!("user {:?} watching game {}", user_id, game.id());
+    universe
+        .send(user_id, &Message::Spectating(game.game_info()))
+        .await;
+    game.send_full_snapshot(user_id).await;
+    Ok(())
+}
+
+async fn on_resume_game<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    current_user_id: &mut Uuid,
+    cmd: ResumeGameCommand,
+) -> Result<(), ProtocolError> {
+    let user_id = *current_user_id;
+    if universe.get_user_game(user_id).await.is_some_and(|game| game.join_code() != cmd.join_code) {
+        universe.remove_user_from_game(user_id).await;
+    }
+    universe.stop_spectating(user_id).await;
+    let (user, game) = universe.resume_game(user_id, cmd.join_code, cmd.player_id, &cmd.token).await?;
+    *current_user_id = user.id;
+    log::info!("user {:?} resumed game {} as {:?}", user_id, game.id(), user.id);
+    universe
+        .send(user.id, &Message::Authenticated(user.clone().into()))
+        .await;
+    send_session_token(&universe, user.id).await;
+    universe
+        .send(user.id, &Message::GameJoined(game.game_info()))
+        .await;
+    game.broadcast_current_state().await;
+    game.send_clocks(user.id).await;
+    Ok(())
+}
+
+async fn on_request_resync<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    user_id: Uuid,
+) -> Result<(), ProtocolError> {
+    let game = match universe.get_user_game(user_id).await {
+        Some(game) => Some(game),
+        None => universe.get_spectated_game(user_id).await,
+    };
+    if let Some(game) = game {
+        game.send_full_snapshot(user_id).await;
+        Ok(())
+    } else {
+        Err(ProtocolError::new(
+            ProtocolErrorKind::BadState,
+            "not in a game",
+        ))
+    }
+}
+
+async fn on_replay<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    user_id: Uuid,
+    cmd: ReplayCommand,
+) -> Result<(), ProtocolError> {
+    if !universe.user_has_capability(user_id, REPLAY_CAPABILITY).await {
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::BadState,
+            "replay capability not enabled",
+        ));
+    }
+    let replayed = universe.replay(user_id, cmd.last_seq).await;
+    log::debug!("{} messages replayed to {:?}", replayed, user_id);
+    Ok(())
+}
+
 async fn on_invite_bot<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
     universe: Arc<Universe<GameStateType, PlayEventT>>,
     user_id: Uuid,
@@ -275,12 +459,55 @@ async fn on_invite_bot<'de, GameStateType:GameState+Default, PlayEventT:Send+Ser
 }
 
 async fn on_leave_game<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(universe: Arc<Universe<GameStateType, PlayEventT>>, user_id: Uuid) -> Result<(), ProtocolError> {
+    if universe.stop_spectating(user_id).await {
+        log::info!("spectator {:?} leaving game", user_id);
+        universe.send(user_id, &Message::GameLeft).await;
+        return Ok(());
+    }
     log::info!(
         "player {:?} leaving game",
         user_id
     );
     universe.remove_user_from_game(user_id).await;
     universe.send(user_id, &Message::GameLeft).await;
+    // The tokens given while in the game must not bring the user back into it
+    universe.revoke_sessions(user_id);
+    send_session_token(&universe, user_id).await;
+    Ok(())
+}
+
+async fn on_rematch<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    user_id: Uuid,
+) -> Result<(), ProtocolError> {
+    let game = universe.get_user_game(user_id).await.ok_or_else(|| ProtocolError::new(
+        ProtocolErrorKind::BadState,
+        "not in a game",
+    ))?;
+    if game.finished_at().is_none() {
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::BadState,
+            "game is not finished",
+        ));
+    }
+    // Games saved before their variant was stored can not be replayed
+    let variant = game.variant().cloned().ok_or_else(|| ProtocolError::new(
+        ProtocolErrorKind::InvalidCommand,
+        "the variant of this game is unknown",
+    ))?;
+
+    if let Some(player_ids) = game.accept_rematch(user_id).await {
+        let new_game = universe.new_game(variant).await;
+        log::info!("rematch of game {} in game {}", game.id(), new_game.id());
+        for player_id in player_ids {
+            game.remove_user(player_id).await;
+            new_game.add_player(player_id).await;
+            universe
+                .send(player_id, &Message::GameJoined(new_game.game_info()))
+                .await;
+        }
+        new_game.broadcast_current_state().await;
+    }
     Ok(())
 }
 
@@ -294,6 +521,100 @@ async fn on_ping<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize
     Ok(())
 }
 
+async fn on_hello<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    user_id: Uuid,
+    cmd: HelloCommand,
+) -> Result<(), ProtocolError> {
+    if cmd.protocol_version < MIN_PROTOCOL_VERSION || cmd.protocol_version > PROTOCOL_VERSION {
+        log::info!("client {} of user {:?} uses unsupported protocol version {}", cmd.client_name, user_id, cmd.protocol_version);
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::UnsupportedVersion,
+            format!("protocol version {} is not supported, the server supports versions {} to {}",
+                cmd.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+        ));
+    }
+    let codec = cmd.codec.as_deref()
+        .and_then(WireCodec::from_name);
+    let current_codec = universe.user_codec(user_id).await;
+    let supported = supported_capabilities(codec.unwrap_or(current_codec));
+    let capabilities = cmd.capabilities.into_iter()
+        .filter(|capability| supported.contains(&capability.as_str()))
+        .collect();
+    log::debug!("client {} of user {:?} enabled {:?}", cmd.client_name, user_id, capabilities);
+    universe.set_user_capabilities(user_id, capabilities).await;
+    universe
+        .send(user_id, &Message::Welcome(WelcomeMessage {
+            protocol_version: PROTOCOL_VERSION,
+            server_version: env!("CARGO_PKG_VERSION").into(),
+            capabilities: supported.iter().map(|capability| capability.to_string()).collect(),
+            codec: codec.unwrap_or(current_codec).name().into(),
+            codecs: WireCodec::ALL.iter().map(|codec| codec.name().to_string()).collect(),
+        }))
+        .await;
+    // The welcome message is still sent with the previous codec
+    if let Some(codec) = codec {
+        universe.set_user_codec(user_id, codec).await;
+    }
+    Ok(())
+}
+
+// The deltas are JSON patches, they are only offered with the JSON codec
+fn supported_capabilities(codec: WireCodec) -> Vec<&'static str> {
+    CAPABILITIES.iter().copied()
+        .filter(|capability| *capability != DELTA_CAPABILITY || codec == WireCodec::Json)
+        .collect()
+}
+
+async fn on_admin_login<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    user_id: Uuid,
+    cmd: AdminLoginCommand,
+) -> Result<(), ProtocolError> {
+    if !universe.admin_settings().check_token(&cmd.token) {
+        log::warn!(target: "audit", "user {:?} admin login refused", user_id);
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::Forbidden,
+            "invalid admin token",
+        ));
+    }
+    universe.set_user_admin(user_id).await;
+    log::info!(target: "audit", "user {:?} logged in as admin", user_id);
+    universe.send(user_id, &Message::AdminAuthenticated).await;
+    Ok(())
+}
+
+// Runs an administration command if the connection has the admin role, every
+// attempt is written to the "audit" log
+async fn as_admin<GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: &Universe<GameStateType, PlayEventT>,
+    user_id: Uuid,
+    action: String,
+    debug_only: bool,
+    handler: impl Future<Output = Result<(), ProtocolError>>,
+) -> Result<(), ProtocolError> {
+    if debug_only && !universe.admin_settings().debug {
+        log::warn!(target: "audit", "user {:?} refused {} : debug commands are disabled", user_id, action);
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::Forbidden,
+            "debug commands are disabled on this server",
+        ));
+    }
+    if !universe.user_is_admin(user_id).await {
+        log::warn!(target: "audit", "user {:?} refused {} : not admin", user_id, action);
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::Forbidden,
+            "this command needs the admin role",
+        ));
+    }
+    let result = handler.await;
+    match &result {
+        Ok(()) => log::info!(target: "audit", "user {:?} {}", user_id, action),
+        Err(err) => log::info!(target: "audit", "user {:?} {} failed : {}", user_id, action, err.message()),
+    }
+    result
+}
+
 async fn on_show_uuid<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
     universe: Arc<Universe<GameStateType, PlayEventT>>,
     user_id: Uuid,
@@ -326,7 +647,7 @@ async fn on_server_status<'de, GameStateType:GameState+Default, PlayEventT:Send+
     let players = universe.show_users(user_id).await;
     let games = universe.show_games().await;
     universe
-        .send(user_id, &Message::ServerStatus(ServerStatus { players, games }))
+        .send(user_id, &Message::ServerStatus(ServerStatus { players, games, store_errors: universe.store_errors() }))
         .await;
     Ok(())
 }
@@ -356,6 +677,18 @@ async fn on_debug_game<'de, GameStateType:GameState+Default, PlayEventT:Send+Ser
     Ok(())
 }
 
+async fn on_import_archive<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: Arc<Universe<GameStateType, PlayEventT>>,
+    user_id: Uuid,
+    cmd: ImportArchiveCommand,
+) -> Result<(), ProtocolError> {
+    let game = universe.import_archive(cmd.game_id, cmd.mode).await?;
+    universe
+        .send(user_id, &Message::ArchiveImported(game.game_extended_info().await))
+        .await;
+    Ok(())
+}
+
 async fn on_player_authenticate<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
     universe: Arc<Universe<GameStateType, PlayEventT>>,
     user_id: Uuid,
@@ -379,10 +712,21 @@ async fn on_player_authenticate<'de, GameStateType:GameState+Default, PlayEventT
     universe
         .send(user_id, &Message::Authenticated(player_info.clone().into()))
         .await;
+    send_session_token(&universe, user_id).await;
 
     Ok(())
 }
 
+/// Sends a new token the user will give to reconnect or resume its game.
+async fn send_session_token<GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
+    universe: &Arc<Universe<GameStateType, PlayEventT>>,
+    user_id: Uuid,
+) {
+    universe
+        .send(user_id, &Message::SessionToken(universe.issue_session(user_id)))
+        .await;
+}
+
 //XXX obsolete ?
 pub async fn on_player_continue<'de, GameStateType:GameState+Default, PlayEventT:Send+Serialize>(
     universe: Arc<Universe<GameStateType, PlayEventT>>,
@@ -418,6 +762,7 @@ pub async fn on_user_send_text<'de, GameStateType:GameState+Default, PlayEventT:
     user_id: Uuid,
     cmd: SendTextCommand,
 ) -> Result<(), ProtocolError> {
+    // The players chat is public, the spectators chat stays among the spectators
     if let Some(game) = universe.get_user_game(user_id).await {
         game.broadcast(&Message::Chat(ChatMessage {
             player_id: user_id,
@@ -425,6 +770,15 @@ pub async fn on_user_send_text<'de, GameStateType:GameState+Default, PlayEventT:
         }))
         .await;
         Ok(())
+    } else if let Some(game) = universe.get_spectated_game(user_id).await {
+        let nickname = universe.get_user(user_id).await.map(|user| user.nickname).unwrap_or_default();
+        game.broadcast_to_spectators(&Message::SpectatorChat(SpectatorChatMessage {
+            spectator_id: user_id,
+            nickname,
+            text: cmd.text,
+        }))
+        .await;
+        Ok(())
     } else {
         Err(ProtocolError::new(
             ProtocolErrorKind::BadState,
@@ -433,19 +787,38 @@ pub async fn on_user_send_text<'de, GameStateType:GameState+Default, PlayEventT:
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn serve<GamePlayCommand: Send+Debug+DeserializeOwned+'static, SetPlayerRoleCommand: Send+Debug+DeserializeOwned+'static,
 GameStateType:GameState+'static, PlayEventT:Serialize+Send+Sync+'static> (
     public_dir: String,
     // db_uri: &str,
-    store: Arc<SledStore<GameStateType>>,
+    store: SharedGameStore<GameStateType>,
+    archives: Option<Arc<dyn ArchiveSink<GameStateType>>>,
+    archive_config: ArchiveConfig,
+    sessions: Sessions,
+    admin: AdminSettings,
     str_bots_socket: String,
     socket: SocketAddr,
+    persist_interval: Duration,
+    evict_after: Option<Duration>,
     on_gameplay: GamePlayHandler<GamePlayCommand, GameStateType, PlayEventT>,
     on_setplayerrole: SetPlayerRoleHandler<SetPlayerRoleCommand, GameStateType, PlayEventT>
 ) 
 where GameStateType::VariantParameters:Serialize+Debug+DeserializeOwned+Send+Sync+'static
 {
-    let universe = Arc::new(Universe::new(store, str_bots_socket));
+    let universe = Arc::new(Universe::new(store, archives.clone(), sessions, admin, str_bots_socket));
+    let restored = universe.restore_games().await;
+    log::info!("{} games restored from store", restored);
+    // Started once the games are restored, so that it knows which ones are in use
+    let (archive_shutdown, archive_shutdown_rx) = watch::channel(false);
+    let archive_task = archives.map(|sink| tokio::spawn(archive::run(universe.clone(), sink, archive_config, archive_shutdown_rx)));
+    tokio::spawn(persistence::write_behind(universe.clone(), persist_interval));
+    tokio::spawn(clock::watch_timeouts(universe.clone()));
+    if let Some(evict_after) = evict_after {
+        tokio::spawn(persistence::evict_idle(universe.clone(), evict_after));
+    }
+
+    let shutdown_universe = universe.clone();
     let make_svc = make_service_fn(move |_| {
         let universe = universe.clone();
         let pdir = public_dir.clone();
@@ -453,17 +826,31 @@ where GameStateType::VariantParameters:Serialize+Debug+DeserializeOwned+Send+Syn
         let routes = warp::path("ws") // Websockets on /ws entry point
             .and(warp::ws())
             .and(warp::path::param()) // enable params on websocket : ws/monparam
+            .and(warp::query::<HashMap<String, String>>()) // session token of a reconnecting player : ws/monparam?token=...
+            .and(warp::header::optional::<String>("sec-websocket-protocol"))
             .and(warp::any().map(move || universe.clone()))
             .and(warp::any().map(move || on_gameplay))
             .and(warp::any().map(move || on_setplayerrole))
             .map(|ws: warp::ws::Ws,
                 guid_uuid,
+                query: HashMap<String, String>,
+                subprotocols: Option<String>,
                 universe: Arc<Universe<GameStateType, PlayEventT>>,
                 on_gameplay: GamePlayHandler<GamePlayCommand, GameStateType, PlayEventT>,
                 on_setplayerrole: SetPlayerRoleHandler<SetPlayerRoleCommand, GameStateType, PlayEventT>,
                 | {
+                // The codec can be chosen with a subprotocol, which must then be acknowledged
+                let codec = subprotocols.as_deref().and_then(WireCodec::from_subprotocols);
+                let token = query.get("token").cloned();
                 // when the connection is upgraded to a websocket
-                ws.on_upgrade(move |ws| on_websocket_connect(universe, guid_uuid, ws, on_gameplay, on_setplayerrole))
+                let mut response = ws.on_upgrade(move |ws| on_websocket_connect(universe, guid_uuid, token, codec.unwrap_or_default(), ws, on_gameplay, on_setplayerrole))
+                    .into_response();
+                if let Some(codec) = codec {
+                    if let Ok(value) = hyper::header::HeaderValue::from_str(&codec.subprotocol()) {
+                        response.headers_mut().insert(hyper::header::SEC_WEBSOCKET_PROTOCOL, value);
+                    }
+                }
+                response
             })
         // .or(warp::fs::dir("public/")); // Static files
         .or(warp::fs::dir(pdir)); // Static files
@@ -477,5 +864,79 @@ where GameStateType::VariantParameters:Serialize+Debug+DeserializeOwned+Send+Syn
     } else {
         Server::bind(&socket)
     };
-    server.serve(make_svc).await.unwrap();
+    server.serve(make_svc).with_graceful_shutdown(shutdown_signal()).await.unwrap();
+
+    let saved = shutdown_universe.flush_dirty_games().await;
+    log::info!("server stopped, {} games saved", saved);
+    let _ = archive_shutdown.send(true);
+    if let Some(archive_task) = archive_task {
+        let _ = archive_task.await;
+    }
+}
+
+/// Resolves when the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{GameStore, MemoryStore};
+    use crate::protocol::test_util::TestState;
+
+    fn make_universe(store: Arc<MemoryStore<TestState>>) -> Arc<Universe<TestState, ()>> {
+        let sessions = Sessions::with_random_secret(chrono::Duration::hours(1));
+        Arc::new(Universe::new(store, None, sessions, AdminSettings::default(), String::new()))
+    }
+
+    #[test]
+    fn offers_deltas_with_the_json_codec_only() {
+        assert!(supported_capabilities(WireCodec::Json).contains(&DELTA_CAPABILITY));
+        for codec in [WireCodec::MessagePack, WireCodec::Cbor] {
+            let capabilities = supported_capabilities(codec);
+            assert!(!capabilities.contains(&DELTA_CAPABILITY));
+            assert!(capabilities.contains(&REPLAY_CAPABILITY));
+        }
+    }
+
+    #[tokio::test]
+    async fn rematch_seats_the_players_in_a_new_game() {
+        let store = Arc::new(MemoryStore::new("memory://"));
+        let universe = make_universe(store.clone());
+        let game = universe.new_game(Variant { parameters: 0, time_control: Default::default() }).await;
+        let mut player_ids = vec![];
+        let mut _frames = vec![];
+        for _ in 0..2 {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let (user, _) = universe.add_user(tx, String::new(), String::new(), None).await;
+            game.add_player(user.id).await;
+            player_ids.push(user.id);
+            _frames.push(rx);
+        }
+        game.state_handle().lock().await.finished = true;
+        game.broadcast_current_state().await;
+        universe.flush_dirty_games().await;
+
+        on_rematch(universe.clone(), player_ids[0]).await.unwrap();
+        assert_eq!(universe.get_user_game(player_ids[0]).await.map(|game| game.id()), Some(game.id()));
+        on_rematch(universe.clone(), player_ids[1]).await.unwrap();
+        let new_game = universe.get_user_game(player_ids[0]).await.unwrap();
+        assert_ne!(new_game.id(), game.id());
+        assert_eq!(universe.get_user_game(player_ids[1]).await.map(|game| game.id()), Some(new_game.id()));
+        assert!(new_game.finished_at().is_none());
+        assert!(universe.get_game(game.id()).await.is_none());
+
+        universe.flush_dirty_games().await;
+        assert_eq!(store.save_count(game.id()), 1);
+        let record = store.load(game.id()).await.unwrap();
+        assert!(record.is_finished());
+        assert!(record.outcome.is_some());
+        assert_eq!(record.state.players.len(), 2);
+    }
 }
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::utils::write_atomically;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and checks the session tokens proving the identity of a user.
+///
+/// A token is `{user_id}.{issued_at}.{expires_at}.{signature}`, dates in
+/// milliseconds, signed with HMAC-SHA256. Tokens issued before the user
+/// left its game are revoked.
+pub struct Sessions {
+    secret: Vec<u8>,
+    ttl: Duration,
+    revoked_before: Mutex<HashMap<Uuid, i64>>,
+}
+
+impl Sessions {
+    pub fn new(secret: &[u8], ttl: Duration) -> Sessions {
+        Sessions {
+            secret: secret.to_vec(),
+            ttl,
+            revoked_before: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Uses a random secret : the tokens are not valid anymore after a restart.
+    pub fn with_random_secret(ttl: Duration) -> Sessions {
+        let secret: [u8; 32] = rand::thread_rng().gen();
+        Sessions::new(&secret, ttl)
+    }
+
+    /// Uses the secret kept in the file at `path`, a random one is written there first if it does not exist.
+    ///
+    /// The tokens stay valid after a restart. Only the owner can read the file.
+    pub fn with_secret_file(path: &Path, ttl: Duration) -> io::Result<Sessions> {
+        let secret = match fs::read_to_string(path) {
+            Ok(secret) => secret,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let secret: [u8; 32] = rand::thread_rng().gen();
+                let secret = base64::encode_config(secret, base64::URL_SAFE_NO_PAD);
+                write_atomically(path, |writer| {
+                    writer.get_ref().set_permissions(fs::Permissions::from_mode(0o600))?;
+                    writer.write_all(secret.as_bytes())
+                })?;
+                secret
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Sessions::new(secret.trim().as_bytes(), ttl))
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(payload.as_bytes());
+        base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Returns a new token for the user, and its expiration date.
+    pub fn issue(&self, user_id: Uuid) -> (String, DateTime<Utc>) {
+        let mut issued_at = Utc::now().timestamp_millis();
+        // A token issued in the same millisecond as a revocation must stay valid
+        if let Some(revoked_before) = self.revoked_before.lock().unwrap().get(&user_id) {
+            issued_at = issued_at.max(revoked_before + 1);
+        }
+        let expires_at = Utc.timestamp_millis(issued_at) + self.ttl;
+        let payload = format!("{}.{}.{}", user_id, issued_at, expires_at.timestamp_millis());
+        let signature = self.sign(&payload);
+        (format!("{}.{}", payload, signature), expires_at)
+    }
+
+    /// Checks that the token was issued to the user, and is still valid.
+    pub fn verify(&self, token: &str, user_id: Uuid) -> bool {
+        let (payload, signature) = match token.rsplit_once('.') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(payload.as_bytes());
+        if mac.verify_slice(&signature).is_err() {
+            return false;
+        }
+
+        let fields: Vec<&str> = payload.split('.').collect();
+        let (token_user, issued_at, expires_at) = match fields.as_slice() {
+            [token_user, issued_at, expires_at] => (token_user, issued_at, expires_at),
+            _ => return false,
+        };
+        let issued_at = issued_at.parse::<i64>().unwrap_or(i64::MIN);
+        let expires_at = expires_at.parse::<i64>().map(|millis| Utc.timestamp_millis(millis));
+        let revoked_before = self.revoked_before.lock().unwrap().get(&user_id).copied();
+        Uuid::parse_str(token_user).is_ok_and(|token_user| token_user == user_id)
+            && expires_at.is_ok_and(|expires_at| expires_at > Utc::now())
+            && revoked_before.is_none_or(|revoked_before| issued_at > revoked_before)
+    }
+
+    /// Revokes all the tokens issued to the user until now.
+    pub fn revoke(&self, user_id: Uuid) {
+        let now = Utc::now().timestamp_millis();
+        let mut revoked_before = self.revoked_before.lock().unwrap();
+        // Tokens issued before the ttl have expired anyway
+        let expired = now - self.ttl.num_milliseconds();
+        revoked_before.retain(|_, revoked| *revoked > expired);
+        revoked_before.insert(user_id, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions() -> Sessions {
+        Sessions::new(b"secret", Duration::hours(1))
+    }
+
+    #[test]
+    fn verifies_tokens_of_their_user() {
+        let sessions = sessions();
+        let user_id = Uuid::new_v4();
+        let (token, expires_at) = sessions.issue(user_id);
+        assert!(expires_at > Utc::now());
+        assert!(sessions.verify(&token, user_id));
+        assert!(!sessions.verify(&token, Uuid::new_v4()));
+        assert!(!sessions.verify("garbage", user_id));
+    }
+
+    #[test]
+    fn rejects_forged_and_expired_tokens() {
+        let user_id = Uuid::new_v4();
+        let (token, _) = sessions().issue(user_id);
+        assert!(!Sessions::new(b"other secret", Duration::hours(1)).verify(&token, user_id));
+
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", payload.replacen(&user_id.to_string(), &Uuid::new_v4().to_string(), 1), signature);
+        assert!(!sessions().verify(&forged, user_id));
+
+        let expired = Sessions::new(b"secret", Duration::milliseconds(-1));
+        let (token, _) = expired.issue(user_id);
+        assert!(!expired.verify(&token, user_id));
+    }
+
+    #[test]
+    fn revokes_previous_tokens_only() {
+        let sessions = sessions();
+        let user_id = Uuid::new_v4();
+        let other_id = Uuid::new_v4();
+        let (old_token, _) = sessions.issue(user_id);
+        let (other_token, _) = sessions.issue(other_id);
+
+        sessions.revoke(user_id);
+        assert!(!sessions.verify(&old_token, user_id));
+        assert!(sessions.verify(&other_token, other_id));
+
+        // Even when issued in the same millisecond as the revocation
+        let (new_token, _) = sessions.issue(user_id);
+        assert!(sessions.verify(&new_token, user_id));
+    }
+
+    #[test]
+    fn keeps_the_secret_file_across_restarts() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("games.session-secret");
+        let user_id = Uuid::new_v4();
+        let (token, _) = Sessions::with_secret_file(&path, Duration::hours(1)).unwrap().issue(user_id);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let restarted = Sessions::with_secret_file(&path, Duration::hours(1)).unwrap();
+        assert!(restarted.verify(&token, user_id));
+    }
+}
@@ -1,21 +1,288 @@
+use std::fmt;
+use std::sync::Arc;
+
 use chrono::{Utc, DateTime};
 use uuid::Uuid;
-use serde::{Serialize, Deserialize};
 use async_trait::async_trait;
 
-use std::convert::From;
-
 use crate::protocol::{GameState, GameRecord};
-use crate::game::{Game, UniverseGame};
+use crate::store_jsondir::JsonDirStore;
+use crate::store_print::PrintStore;
+use crate::store_sled::SledStore;
+pub use crate::store_sled::SledEncoding;
+use crate::store_sqlite::SqliteStore;
+
+pub use crate::store_memory::MemoryStore;
+
+pub type GameRecordIterator<'a, GameStateT> = Box<dyn Iterator<Item=Result<GameRecord<GameStateT>, StoreError>> + 'a>;
+
+/// Error returned by the store backends.
+#[derive(Debug)]
+pub enum StoreError {
+    /// The record could not be encoded or decoded
+    Serialization(String),
+    /// The backend could not read or write the record
+    Io(String),
+    /// There is no record for this game
+    NotFound(Uuid),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Serialization(err) => write!(f, "serialization error: {}", err),
+            StoreError::Io(err) => write!(f, "i/o error: {}", err),
+            StoreError::NotFound(game_id) => write!(f, "game {} not found", game_id),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Io(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        if err.is_io() {
+            StoreError::Io(err.to_string())
+        } else {
+            StoreError::Serialization(err.to_string())
+        }
+    }
+}
+
+impl From<sled::Error> for StoreError {
+    fn from(err: sled::Error) -> Self {
+        StoreError::Io(err.to_string())
+    }
+}
+
+impl From<bincode::Error> for StoreError {
+    fn from(err: bincode::Error) -> Self {
+        match *err {
+            bincode::ErrorKind::Io(err) => StoreError::Io(err.to_string()),
+            err => StoreError::Serialization(err.to_string()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        StoreError::Io(err.to_string())
+    }
+}
+
+/// A store backend shared by the universe and the background tasks
+pub type SharedGameStore<GameStateT> = Arc<dyn GameStore<GameStateT=GameStateT> + Send + Sync>;
 
 #[async_trait]
 pub trait GameStore {
     type GameStateT: GameState;
-    // type ItemIterator: Iterator<Item=GameRecord<Self::GameStateT>>;
 
-    fn new( path: &str ) -> Self;
-    async fn save(&self, game: &dyn UniverseGame<Self::GameStateT> ) -> bool;
-    async fn delete(&self, game_id: Uuid ) -> bool;
-    // fn iter(&self) -> Self::ItemIterator;
+    fn new( path: &str ) -> Self where Self: Sized;
+    async fn save(&self, record: GameRecord<Self::GameStateT> ) -> Result<(), StoreError>;
+    async fn delete(&self, game_id: Uuid ) -> Result<(), StoreError>;
+
+    /// Returns the stored record of a game.
+    async fn load(&self, game_id: Uuid) -> Result<GameRecord<Self::GameStateT>, StoreError>;
+
+    /// Returns at most `limit` records, skipping the first `offset` ones.
+    ///
+    /// Records which could not be decoded are logged and skipped.
+    async fn list(&self, offset: usize, limit: usize) -> Result<Vec<GameRecord<Self::GameStateT>>, StoreError> {
+        Ok(skip_errors(self.iter()).skip(offset).take(limit).collect())
+    }
+
+    /// Returns the records which were last updated before `date`.
+    ///
+    /// Records which could not be decoded are logged and skipped.
+    async fn list_updated_before(&self, date: DateTime<Utc>) -> Result<Vec<GameRecord<Self::GameStateT>>, StoreError> {
+        Ok(skip_errors(self.iter()).filter(|record| record.date_updated < date).collect())
+    }
+
+    /// Returns the stored record of the game with this join code.
+    ///
+    /// Backends without a join code index scan all the records.
+    async fn find_by_join_code(&self, join_code: &str) -> Result<Option<GameRecord<Self::GameStateT>>, StoreError> {
+        Ok(skip_errors(self.iter()).find(|record| record.info.join_code == join_code))
+    }
+
+    /// Iterates over all the stored records.
+    ///
+    /// The records are read as the iteration goes, they are not all loaded at
+    /// once. Records which could not be decoded are returned as errors.
+    fn iter(&self) -> GameRecordIterator<'_, Self::GameStateT>;
+}
+
+/// Logs and skips the records which could not be read.
+pub fn skip_errors<'a, GameStateT: GameState>(records: GameRecordIterator<'a, GameStateT>) -> impl Iterator<Item=GameRecord<GameStateT>> + 'a {
+    records.filter_map(|record| record.map_err(|err| error!("could not read stored game: {}", err)).ok())
+}
+
+/// Runs a store operation on a blocking thread.
+///
+/// The backends do blocking i/o, running them there keeps slow backends from
+/// stalling the executor.
+pub async fn run_blocking<GameStateT: GameState, T: Send + 'static>(
+    store: &SharedGameStore<GameStateT>,
+    operation: impl FnOnce(&SharedGameStore<GameStateT>) -> Result<T, StoreError> + Send + 'static,
+) -> Result<T, StoreError> {
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || operation(&store)).await
+        .unwrap_or_else(|err| Err(StoreError::Io(err.to_string())))
+}
+
+/// Opens the store backend described by `uri`.
+///
+/// The backend is chosen from the uri scheme:
+/// - `sled://path?encoding=json` : sled database at `path`, the encoding
+///   is one of `bincode` (default), `json` or `cbor`
+/// - `sqlite://path` : SQLite database at `path`
+/// - `json://directory` : one JSON file per game in `directory`
+/// - `memory://` : records are kept in memory until the server stops
+/// - `print://` : nothing is persisted
+///
+/// An uri without scheme is treated as a sled database path.
+pub fn open_store<GameStateT: GameState>(uri: &str) -> Result<SharedGameStore<GameStateT>, String> {
+    let (scheme, path, query) = parse_uri(uri);
+    let param = |name: &str| query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value);
+
+    match scheme {
+        "sled" => {
+            let encoding = param("encoding").unwrap_or("bincode").parse().map_err(|err: StoreError| err.to_string())?;
+            let store = SledStore::open(path, encoding).map_err(|err| err.to_string())?;
+            Ok(Arc::new(store))
+        }
+        "sqlite" => {
+            let store = SqliteStore::open(path).map_err(|err| err.to_string())?;
+            Ok(Arc::new(store))
+        }
+        "json" => {
+            let store = JsonDirStore::open(path).map_err(|err| err.to_string())?;
+            Ok(Arc::new(store))
+        }
+        "memory" => Ok(Arc::new(MemoryStore::new(path))),
+        "print" => Ok(Arc::new(PrintStore::new(path))),
+        _ => Err(format!("unknown store scheme '{}'", scheme)),
+    }
+}
+
+// Splits a store uri into its scheme, path and query
+fn parse_uri(uri: &str) -> (&str, &str, &str) {
+    let (scheme, path) = match uri.find("://") {
+        Some(idx) => (&uri[..idx], &uri[idx + 3..]),
+        None => ("sled", uri),
+    };
+    match path.find('?') {
+        Some(idx) => (scheme, &path[..idx], &path[idx + 1..]),
+        None => (scheme, path, ""),
+    }
+}
+
+/// Path of the database of the store described by `uri`, `None` for the
+/// stores which do not keep the games after a restart.
+pub fn store_path(uri: &str) -> Option<&str> {
+    match parse_uri(uri) {
+        ("memory", _, _) | ("print", _, _) => None,
+        (_, path, _) => Some(path),
+    }
 }
 
+/// Copies all the records of a store into another one.
+///
+/// This is used to convert a database to another encoding or backend
+/// while the server is stopped. Returns the number of records copied and
+/// the number of records which could not be read.
+pub async fn copy_records<GameStateT: GameState>(from: &SharedGameStore<GameStateT>, to: &SharedGameStore<GameStateT>) -> Result<(usize, usize), StoreError> {
+    let mut records = vec![];
+    let mut failed = 0;
+    for record in from.iter() {
+        match record {
+            Ok(record) => records.push(record),
+            Err(err) => {
+                failed += 1;
+                error!("could not read stored game: {}", err);
+            }
+        }
+    }
+    let copied = records.len();
+    for record in records {
+        to.save(record).await?;
+    }
+    Ok((copied, failed))
+}
+
+/// Checks a backend against the behaviour expected from every store.
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::protocol::{GameInfo, Variant};
+    use crate::protocol::test_util::TestState;
+
+    fn date(date: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(date).unwrap().with_timezone(&Utc)
+    }
+
+    fn record(game_id: u128, join_code: &str, score: u32, date_updated: &str) -> GameRecord<TestState> {
+        let info = GameInfo { game_id: Uuid::from_u128(game_id), join_code: join_code.into() };
+        let mut record = GameRecord::create(TestState { score, ..Default::default() }, info);
+        record.date_updated = date(date_updated);
+        record
+    }
+
+    /// Saves, loads, finds, lists and deletes records through `store`, which must be empty.
+    pub async fn check_round_trips(store: SharedGameStore<TestState>) {
+        let mut finished = record(1, "BCDFGH", 3, "2024-01-01T10:00:00Z");
+        finished.finished_at = Some(date("2024-01-01T10:00:00Z"));
+        finished.variant = Some(Variant { parameters: 2, time_control: Default::default() });
+        let ongoing = record(2, "JKLMNP", 5, "2024-01-02T10:00:00Z");
+        store.save(finished.clone()).await.unwrap();
+        store.save(ongoing.clone()).await.unwrap();
+
+        let loaded = store.load(finished.info.game_id).await.unwrap();
+        assert_eq!(loaded.info.join_code, "BCDFGH");
+        assert_eq!(loaded.state.score, 3);
+        assert_eq!(loaded.date_updated, finished.date_updated);
+        assert_eq!(loaded.finished_at, finished.finished_at);
+        assert_eq!(loaded.variant.map(|variant| variant.parameters), Some(2));
+        assert!(matches!(store.load(Uuid::from_u128(3)).await, Err(StoreError::NotFound(_))));
+
+        let found = store.find_by_join_code("JKLMNP").await.unwrap().unwrap();
+        assert_eq!(found.info.game_id, ongoing.info.game_id);
+        assert!(store.find_by_join_code("QRSTVW").await.unwrap().is_none());
+
+        let before = store.list_updated_before(date("2024-01-02T00:00:00Z")).await.unwrap();
+        assert_eq!(before.iter().map(|record| record.info.game_id).collect::<Vec<_>>(), vec![finished.info.game_id]);
+        assert_eq!(store.iter().count(), 2);
+
+        store.delete(finished.info.game_id).await.unwrap();
+        assert!(matches!(store.load(finished.info.game_id).await, Err(StoreError::NotFound(_))));
+        assert!(matches!(store.delete(finished.info.game_id).await, Err(StoreError::NotFound(_))));
+        let remaining: Vec<_> = skip_errors(store.iter()).map(|record| record.info.game_id).collect();
+        assert_eq!(remaining, vec![ongoing.info.game_id]);
+    }
+
+    #[tokio::test]
+    async fn opens_every_backend_from_its_uri() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = |name: &str| directory.path().join(name).to_str().unwrap().to_string();
+        let uris = [
+            format!("sled://{}", path("bincode")),
+            format!("sled://{}?encoding=json", path("json")),
+            format!("sled://{}?encoding=cbor", path("cbor")),
+            format!("sqlite://{}", path("games.db")),
+            format!("json://{}", path("games")),
+            "memory://".to_string(),
+        ];
+        for uri in uris {
+            check_round_trips(open_store(&uri).unwrap()).await;
+        }
+    }
+}
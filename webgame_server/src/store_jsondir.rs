@@ -0,0 +1,117 @@
+use std::fs::{self, File};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::protocol::{ GameState, GameRecord };
+use crate::store::{GameStore, GameRecordIterator, StoreError};
+use crate::utils::write_atomically;
+
+/// Stores each game as `{directory}/{game_id}.json`, the format used for archives.
+///
+/// Records are first written to a temporary file which is then renamed, so
+/// that a crash never leaves a half-written record.
+pub struct JsonDirStore<GameStateType> {
+    _phantom: PhantomData<GameStateType>,
+    directory: PathBuf,
+}
+
+impl<GameStateType: GameState> JsonDirStore<GameStateType> {
+    /// Opens the store in `path`, creating the directory if needed.
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        fs::create_dir_all(path)?;
+        Ok(JsonDirStore {
+            _phantom: PhantomData,
+            directory: PathBuf::from(path),
+        })
+    }
+
+    fn record_path(&self, game_id: Uuid) -> PathBuf {
+        self.directory.join(format!("{}.json", game_id))
+    }
+
+    fn read_record(path: &PathBuf) -> Result<GameRecord<GameStateType>, StoreError> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    fn write_record(&self, record: &GameRecord<GameStateType>) -> Result<(), StoreError> {
+        let path = self.record_path(record.info.game_id);
+        write_atomically(&path, |writer| Ok(serde_json::to_writer(writer, record)?))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<GameStateType: GameState> GameStore for JsonDirStore<GameStateType> {
+    type GameStateT = GameStateType;
+
+    fn new( path: &str ) -> Self {
+        Self::open(path).expect("Failed to create json store directory")
+    }
+
+    async fn save(&self, record: GameRecord<GameStateType> ) -> Result<(), StoreError> {
+        self.write_record(&record)
+    }
+
+    async fn delete(&self, game_id: Uuid) -> Result<(), StoreError> {
+        let path = self.record_path(game_id);
+        if !path.exists() {
+            return Err(StoreError::NotFound(game_id));
+        }
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    async fn load(&self, game_id: Uuid) -> Result<GameRecord<GameStateType>, StoreError> {
+        let path = self.record_path(game_id);
+        if !path.exists() {
+            return Err(StoreError::NotFound(game_id));
+        }
+        Self::read_record(&path)
+    }
+
+    fn iter(&self) -> GameRecordIterator<'_, GameStateType> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.directory)
+            .map(|entries| entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                .filter(|path| path.file_name().is_some_and(|name| !name.to_string_lossy().starts_with('.')))
+                .collect())
+            .unwrap_or_default();
+        paths.sort();
+        Box::new(paths.into_iter().map(|path| {
+            Self::read_record(&path)
+                .map_err(|err| StoreError::Serialization(format!("{:?}: {}", path, err)))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::protocol::test_util::TestState;
+    use crate::store::tests::check_round_trips;
+
+    #[tokio::test]
+    async fn round_trips_records() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = JsonDirStore::<TestState>::open(directory.path().to_str().unwrap()).unwrap();
+        check_round_trips(Arc::new(store)).await;
+    }
+
+    #[tokio::test]
+    async fn reports_unreadable_files_and_skips_temporary_ones() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = JsonDirStore::<TestState>::open(directory.path().to_str().unwrap()).unwrap();
+        fs::write(directory.path().join(".partial.json"), "{").unwrap();
+        fs::write(directory.path().join("broken.json"), "{").unwrap();
+        let records: Vec<_> = store.iter().collect();
+        assert_eq!(records.len(), 1);
+        assert!(matches!(records[0], Err(StoreError::Serialization(_))));
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::sync::Mutex;
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::protocol::{ GameState, GameRecord };
+use crate::store::{GameStore, GameRecordIterator, StoreError};
+
+struct MemoryStoreData<GameStateType: GameState> {
+    games: BTreeMap<Uuid, GameRecord<GameStateType>>,
+    saves: BTreeMap<Uuid, usize>,
+}
+
+/// Keeps the game records in memory.
+///
+/// Nothing survives a restart : this store is meant for tests, which can
+/// inspect what the server would have persisted.
+pub struct MemoryStore<GameStateType: GameState> {
+    data: Mutex<MemoryStoreData<GameStateType>>,
+}
+
+impl<GameStateType: GameState> MemoryStore<GameStateType> {
+    /// Number of times the game has been saved.
+    pub fn save_count(&self, game_id: Uuid) -> usize {
+        self.data.lock().unwrap().saves.get(&game_id).copied().unwrap_or(0)
+    }
+
+    /// Game state as it was last saved.
+    pub fn last_saved_state(&self, game_id: Uuid) -> Option<GameStateType> {
+        self.data.lock().unwrap().games.get(&game_id).map(|record| record.state.clone())
+    }
+
+    /// All the records currently stored.
+    pub fn records(&self) -> Vec<GameRecord<GameStateType>> {
+        self.data.lock().unwrap().games.values().cloned().collect()
+    }
+
+    /// Number of records currently stored.
+    pub fn len(&self) -> usize {
+        self.data.lock().unwrap().games.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl<GameStateType: GameState> GameStore for MemoryStore<GameStateType> {
+    type GameStateT = GameStateType;
+
+    fn new( _path: &str ) -> Self {
+        MemoryStore {
+            data: Mutex::new(MemoryStoreData {
+                games: BTreeMap::new(),
+                saves: BTreeMap::new(),
+            }),
+        }
+    }
+
+    async fn save(&self, record: GameRecord<GameStateType> ) -> Result<(), StoreError> {
+        let mut data = self.data.lock().unwrap();
+        *data.saves.entry(record.info.game_id).or_insert(0) += 1;
+        data.games.insert(record.info.game_id, record);
+        Ok(())
+    }
+
+    async fn delete(&self, game_id: Uuid) -> Result<(), StoreError> {
+        self.data.lock().unwrap().games.remove(&game_id)
+            .map(|_| ())
+            .ok_or(StoreError::NotFound(game_id))
+    }
+
+    async fn load(&self, game_id: Uuid) -> Result<GameRecord<GameStateType>, StoreError> {
+        self.data.lock().unwrap().games.get(&game_id).cloned()
+            .ok_or(StoreError::NotFound(game_id))
+    }
+
+    // The records are cloned one at a time, the games saved meanwhile after
+    // the current one are returned too
+    fn iter(&self) -> GameRecordIterator<'_, GameStateType> {
+        let mut last_id = None;
+        Box::new(std::iter::from_fn(move || {
+            let data = self.data.lock().unwrap();
+            let next = match last_id {
+                Some(game_id) => data.games.range((Excluded(game_id), Unbounded)).next(),
+                None => data.games.iter().next(),
+            };
+            next.map(|(game_id, record)| {
+                last_id = Some(*game_id);
+                Ok(record.clone())
+            })
+        }))
+    }
+}
@@ -3,8 +3,7 @@ use async_trait::async_trait;
 use uuid::Uuid;
 
 use crate::protocol::{ GameState, GameRecord };
-use crate::store::GameStore;
-use crate::game::{Game, UniverseGame};
+use crate::store::{GameStore, GameRecordIterator, StoreError};
 
 pub struct PrintStore<GameStateType> {
     _phantom: PhantomData<GameStateType>
@@ -15,21 +14,28 @@ impl<GameStateType: GameState> GameStore for PrintStore<GameStateType> {
 // impl<GameStateType> GameStore for PrintStore {
     type GameStateT = GameStateType;
 
-    fn new( path: &str ) -> Self {
+    fn new( _path: &str ) -> Self {
         // println!("Creating dummy print store with path {}", path);
         PrintStore {
             _phantom: PhantomData
         }
     }
 
-    async fn save(&self, game: &dyn UniverseGame<GameStateType> ) -> bool {
-    // fn save<State: GameState>(&self, game: &dyn UniverseGame<GameStateType> ) -> bool {
-        // println!("Storing {:?}", game.get_info());
-        true
+    async fn save(&self, _record: GameRecord<GameStateType> ) -> Result<(), StoreError> {
+        // println!("Storing {:?}", record.info);
+        Ok(())
     }
 
-    async fn delete(&self, game_id: Uuid ) -> bool {
+    async fn delete(&self, _game_id: Uuid ) -> Result<(), StoreError> {
         // println!("Deleting {}", game_id);
-        true
+        Ok(())
+    }
+
+    async fn load(&self, game_id: Uuid) -> Result<GameRecord<GameStateType>, StoreError> {
+        Err(StoreError::NotFound(game_id))
+    }
+
+    fn iter(&self) -> GameRecordIterator<'_, GameStateType> {
+        Box::new(std::iter::empty())
     }
 }
@@ -1,78 +1,268 @@
+use std::fmt;
 use std::marker::PhantomData;
+use std::str::FromStr;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer};
 use uuid::Uuid;
 
-use sled_extensions::bincode::Tree;
-use sled_extensions::DbExt; // for open_bincode_tree
-use sled_extensions::Error; // for open_bincode_tree
+use crate::protocol::{ GameState, GameInfo, GameRecord, deserialize_state };
+use crate::store::{GameStore, GameRecordIterator, StoreError};
 
-use crate::protocol::{ GameState, GameRecord };
-use crate::store::GameStore;
-use crate::game::{Game, UniverseGame};
+// Layout of the records stored before schema versioning
+#[derive(Deserialize)]
+struct LegacyGameRecord<State: GameState> {
+    date_updated: DateTime<Utc>,
+    info: GameInfo,
+    #[serde(deserialize_with = "deserialize_legacy_state")]
+    state: State,
+}
+
+fn deserialize_legacy_state<'de, State: GameState, D: Deserializer<'de>>(deserializer: D) -> Result<State, D::Error> {
+    deserialize_state(0, deserializer)
+}
+
+impl<State: GameState> From<LegacyGameRecord<State>> for GameRecord<State> {
+    fn from(record: LegacyGameRecord<State>) -> Self {
+        GameRecord {
+            schema_version: State::SCHEMA_VERSION,
+            date_updated: record.date_updated,
+            info: record.info,
+            state: record.state,
+            finished_at: None,
+            outcome: None,
+            variant: None,
+            clocks: None,
+        }
+    }
+}
 
+// Key of the encoding in the metadata tree
+const ENCODING_KEY: &[u8] = b"encoding";
+
+/// Encoding of the records in a sled database.
+///
+/// Bincode is the fastest, JSON and CBOR are self-describing : they are
+/// more tolerant to schema changes and can be inspected with other tools.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SledEncoding {
+    Bincode,
+    Json,
+    Cbor,
+}
+
+impl FromStr for SledEncoding {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(SledEncoding::Bincode),
+            "json" => Ok(SledEncoding::Json),
+            "cbor" => Ok(SledEncoding::Cbor),
+            _ => Err(StoreError::Serialization(format!("unknown encoding '{}'", s))),
+        }
+    }
+}
 
+impl fmt::Display for SledEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SledEncoding::Bincode => "bincode",
+            SledEncoding::Json => "json",
+            SledEncoding::Cbor => "cbor",
+        })
+    }
+}
+
+impl SledEncoding {
+    fn encode<GameStateType: GameState>(&self, record: &GameRecord<GameStateType>) -> Result<Vec<u8>, StoreError> {
+        match self {
+            SledEncoding::Bincode => Ok(bincode::serialize(record)?),
+            SledEncoding::Json => Ok(serde_json::to_vec(record)?),
+            SledEncoding::Cbor => serde_cbor::to_vec(record)
+                .map_err(|err| StoreError::Serialization(err.to_string())),
+        }
+    }
+
+    fn decode<GameStateType: GameState>(&self, value: &[u8]) -> Result<GameRecord<GameStateType>, String> {
+        match self {
+            SledEncoding::Bincode => bincode::deserialize::<GameRecord<GameStateType>>(value)
+                .or_else(|err| {
+                    bincode::deserialize::<LegacyGameRecord<GameStateType>>(value)
+                        .map(GameRecord::from)
+                        .map_err(|_| err)
+                })
+                .map_err(|err| err.to_string()),
+            SledEncoding::Json => serde_json::from_slice(value).map_err(|err| err.to_string()),
+            SledEncoding::Cbor => serde_cbor::from_slice(value).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// Stores the games in a sled database.
+///
+/// The encoding is chosen when the database is created and kept in its
+/// metadata, databases created before that are bincode encoded.
+///
+/// The `join_codes` tree indexes the game ids by join code. An entry may
+/// outlive the join code of its game, it is checked against the record.
 pub struct SledStore<GameStateType: GameState+Clone> {
     _phantom: PhantomData<GameStateType>,
-    games: Tree<GameRecord<GameStateType>>,
+    games: sled::Tree,
+    join_codes: sled::Tree,
+    encoding: SledEncoding,
 }
 
 impl<GameStateType: GameState+Clone> SledStore<GameStateType> {
-    pub fn data(&self) -> &sled_extensions::structured::Tree<GameRecord<GameStateType>, sled_extensions::bincode::BincodeEncoding> {
-        &self.games
+    /// Opens the database at `path`.
+    ///
+    /// Fails if the database already contains records with another encoding.
+    pub fn open(path: &str, encoding: SledEncoding) -> Result<Self, StoreError> {
+        let db = sled::Config::default().path(path).open()?;
+        let games = db.open_tree("games")?;
+        let meta = db.open_tree("meta")?;
+        let stored_encoding = match meta.get(ENCODING_KEY)? {
+            Some(value) => Some(String::from_utf8_lossy(&value).parse()?),
+            None if games.is_empty() => None,
+            None => Some(SledEncoding::Bincode),
+        };
+        match stored_encoding {
+            Some(stored) if stored != encoding => {
+                return Err(StoreError::Serialization(format!(
+                    "database {} is {} encoded, not {}", path, stored, encoding
+                )));
+            }
+            Some(_) => {}
+            None => { meta.insert(ENCODING_KEY, encoding.to_string().as_bytes())?; }
+        }
+        let store = SledStore {
+            _phantom: PhantomData,
+            join_codes: db.open_tree("join_codes")?,
+            games,
+            encoding,
+        };
+        if store.join_codes.is_empty() && !store.games.is_empty() {
+            store.rebuild_join_codes()?;
+        }
+        Ok(store)
     }
-}
 
-// impl<GameStateType: GameState> IntoIterator for SledStore<GameStateType> {
-//     type Item = GameRecord<GameStateType>;
-//     // type Item = sled::Result<(sled::IVec, sled::IVec)>;
-//     type IntoIter = sled_extensions::structured::Iter<Self::Item, sled_extensions::bincode::BincodeEncoding>;
-//
-//     fn into_iter(self) -> Self::IntoIter {
-//         self.games.into_iter()
-//     }
-// }
+    // Databases created before the join code index have their records indexed when opened
+    fn rebuild_join_codes(&self) -> Result<(), StoreError> {
+        for item in self.games.iter() {
+            let (key, value) = item?;
+            match self.decode(&key, &value) {
+                Ok(record) => { self.join_codes.insert(record.info.join_code.as_bytes(), &key)?; }
+                Err(err) => error!("could not index stored game: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(&self, key: &[u8], value: &[u8]) -> Result<GameRecord<GameStateType>, StoreError> {
+        self.encoding.decode(value)
+            .map_err(|err| {
+                let game_id = Uuid::from_slice(key).map(|id| id.to_string()).unwrap_or_default();
+                StoreError::Serialization(format!("game {}: {}", game_id, err))
+            })
+    }
+}
 
 #[async_trait]
 impl<GameStateType: GameState+Clone> GameStore for SledStore<GameStateType> {
     type GameStateT = GameStateType;
-    // type ItemIterator = sled::Iter;
-    //
-    // fn iter(&self) -> Self::ItemIterator {
-    //     self.games.iter()
-    // }
 
     fn new( path: &str ) -> Self {
-        let games = sled_extensions::Config::default()
-            .path(path).open()
-            .expect("Failed to open sled db")
-            .open_bincode_tree("games")
-            .expect("Failed to open games tree");
-        SledStore {
-            _phantom: PhantomData,
-            games
-        }
+        Self::open(path, SledEncoding::Bincode).expect("Failed to open sled db")
     }
 
+    async fn save(&self, record: GameRecord<GameStateType> ) -> Result<(), StoreError> {
+        let value = self.encoding.encode(&record)?;
+        self.games.insert(record.info.game_id.as_bytes(), value)?;
+        self.join_codes.insert(record.info.join_code.as_bytes(), record.info.game_id.as_bytes())?;
+        Ok(())
+    }
 
-    async fn save(&self, game: &dyn UniverseGame<GameStateType> ) -> bool {
-    // async fn save(&self, game: &dyn UniverseGame<GameStateType> ) -> Result<(), Error> {
-        let info = game.get_info();
-        // println!("Storing {:?}", info);
+    async fn delete(&self, game_id: Uuid) -> Result<(), StoreError> {
+        let value = self.games.remove(game_id.as_bytes())?
+            .ok_or(StoreError::NotFound(game_id))?;
+        // The index entry of an undecodable record is left, it does not lead to any record anymore
+        if let Ok(record) = self.decode(game_id.as_bytes(), &value) {
+            // Only removed if the join code was not given to another game since
+            let _ = self.join_codes.compare_and_swap(record.info.join_code.as_bytes(), Some(game_id.as_bytes()), None::<&[u8]>)?;
+        }
+        Ok(())
+    }
 
-        let game_state = game.get_state(); // Arc<Mutex<GameState>>
-        let game_state = game_state.lock().await; // MutexGuard<GameState>
-        let mystate = (*game_state).clone();
-        let do_steps = || -> Result<(), Error> {
-            // self.games.insert(info.game_id.as_bytes(), mystate.into())?;
-            self.games.insert(info.game_id.as_bytes(), GameRecord::create(mystate, info.clone()))?;
-            Ok(())
+    async fn load(&self, game_id: Uuid) -> Result<GameRecord<GameStateType>, StoreError> {
+        let value = self.games.get(game_id.as_bytes())?
+            .ok_or(StoreError::NotFound(game_id))?;
+        self.decode(game_id.as_bytes(), &value)
+    }
+
+    async fn find_by_join_code(&self, join_code: &str) -> Result<Option<GameRecord<GameStateType>>, StoreError> {
+        let game_id = match self.join_codes.get(join_code.as_bytes())? {
+            Some(game_id) => game_id,
+            None => return Ok(None),
+        };
+        let value = match self.games.get(&game_id)? {
+            Some(value) => value,
+            None => return Ok(None),
         };
-        if let Err(_err) = do_steps() { false } else { true }
+        let record = self.decode(&game_id, &value)?;
+        Ok(Some(record).filter(|record| record.info.join_code == join_code))
     }
 
-    async fn delete(&self, game_id: Uuid) -> bool {
-        let res = self.games.remove(game_id.as_bytes());
-        if let Err(_err) = res { false } else { true }
+    fn iter(&self) -> GameRecordIterator<'_, GameStateType> {
+        Box::new(self.games.iter().map(move |item| {
+            let (key, value) = item?;
+            self.decode(&key, &value)
+        }))
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::*;
+    use crate::protocol::{StateMigration, Variant};
+    use crate::protocol::test_util::{TestState, TestStateV0};
+
+    fn game_info() -> GameInfo {
+        GameInfo { game_id: Uuid::new_v4(), join_code: "BCDFGH".into() }
+    }
+
+    #[test]
+    fn decodes_legacy_bincode_record() {
+        // Written by sled-extensions, before schema versioning
+        #[derive(Serialize)]
+        struct Legacy {
+            date_updated: DateTime<Utc>,
+            info: GameInfo,
+            state: TestStateV0,
+        }
+        let legacy = Legacy { date_updated: Utc::now(), info: game_info(), state: TestStateV0 { players: Default::default(), points: 12 } };
+        let value = bincode::serialize(&legacy).unwrap();
+        let record: GameRecord<TestState> = SledEncoding::Bincode.decode(&value).unwrap();
+        assert_eq!(record.date_updated, legacy.date_updated);
+        assert_eq!(record.info.game_id, legacy.info.game_id);
+        assert_eq!(record.state.score, 12);
+        assert_eq!(record.schema_version, TestState::SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn round_trips_records() {
+        let mut record = GameRecord::create(TestState { score: 8, ..Default::default() }, game_info());
+        record.finished_at = Some(Utc::now());
+        record.variant = Some(Variant { parameters: 2, time_control: Default::default() });
+        for encoding in [SledEncoding::Bincode, SledEncoding::Json, SledEncoding::Cbor] {
+            let value = encoding.encode(&record).unwrap();
+            let decoded: GameRecord<TestState> = encoding.decode(&value).unwrap();
+            assert_eq!(decoded.state.score, 8, "{}", encoding);
+            assert_eq!(decoded.finished_at, record.finished_at, "{}", encoding);
+            assert_eq!(decoded.variant.map(|variant| variant.parameters), Some(2), "{}", encoding);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, Row};
+use uuid::Uuid;
+
+use crate::protocol::{ GameState, GameInfo, GameRecord, deserialize_state };
+use crate::store::{GameStore, GameRecordIterator, StoreError};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS games (
+        game_id TEXT PRIMARY KEY NOT NULL,
+        join_code TEXT NOT NULL,
+        date_updated TEXT NOT NULL,
+        player_ids TEXT NOT NULL,
+        schema_version INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        finished_at TEXT,
+        outcome TEXT,
+        variant TEXT,
+        clocks TEXT
+    );
+    CREATE INDEX IF NOT EXISTS games_date_updated ON games (date_updated);
+    CREATE INDEX IF NOT EXISTS games_join_code ON games (join_code);
+";
+
+// Number of records read at once by `iter`
+const ITER_PAGE_SIZE: usize = 100;
+
+// Record read by `iter` with its game id, which is kept when it can not be decoded
+type PageRow<GameStateType> = (String, Result<GameRecord<GameStateType>, StoreError>);
+
+const SELECT_RECORD: &str = "SELECT game_id, join_code, date_updated, schema_version, state, finished_at, outcome, variant, clocks FROM games";
+
+/// Stores the games in a SQLite database.
+///
+/// Game ids, join codes, update dates and player ids are kept in their own
+/// columns so that they can be queried with SQL, the game state is stored
+/// as JSON along with its schema version. `player_ids` is a JSON array of uuids.
+/// `finished_at` and the JSON `outcome` are null until the game is finished,
+/// the JSON `variant` is null for the games saved before it was stored and
+/// the JSON `clocks` for the games without time control.
+pub struct SqliteStore<GameStateType> {
+    _phantom: PhantomData<GameStateType>,
+    connection: Mutex<Connection>,
+}
+
+// Dates are stored with a fixed precision so that they can be compared as text
+fn format_date(date: &DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+impl<GameStateType: GameState> SqliteStore<GameStateType> {
+    /// Opens the database at `path`, creating the games table if needed.
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteStore {
+            _phantom: PhantomData,
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn select(&self, clause: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<Result<GameRecord<GameStateType>, StoreError>>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let query = format!("{} {}", SELECT_RECORD, clause);
+        let mut statement = connection.prepare(&query)?;
+        let rows = statement.query_map(params, Self::read_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    // Reads the records following the game `after` by order of game id, with their ids
+    fn select_page(&self, after: &str) -> Result<Vec<PageRow<GameStateType>>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let query = format!("{} WHERE game_id > ?1 ORDER BY game_id LIMIT ?2", SELECT_RECORD);
+        let mut statement = connection.prepare(&query)?;
+        let rows = statement.query_map(params![after, ITER_PAGE_SIZE as i64], |row| Ok((row.get::<_, String>(0)?, Self::read_row(row)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    // Same as `select`, rows which could not be decoded are logged and skipped
+    fn select_decoded(&self, clause: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<GameRecord<GameStateType>>, StoreError> {
+        Ok(self.select(clause, params)?
+            .into_iter()
+            .filter_map(|row| row.map_err(|err| error!("sqlite store: {}", err)).ok())
+            .collect())
+    }
+
+    fn read_row(row: &Row) -> rusqlite::Result<Result<GameRecord<GameStateType>, StoreError>> {
+        let game_id: String = row.get(0)?;
+        let join_code: String = row.get(1)?;
+        let date_updated: String = row.get(2)?;
+        let schema_version: u32 = row.get(3)?;
+        let state: String = row.get(4)?;
+        let finished_at: Option<String> = row.get(5)?;
+        let outcome: Option<String> = row.get(6)?;
+        let variant: Option<String> = row.get(7)?;
+        let clocks: Option<String> = row.get(8)?;
+
+        let decode = || -> Result<GameRecord<GameStateType>, String> {
+            Ok(GameRecord {
+                schema_version: GameStateType::SCHEMA_VERSION,
+                date_updated: DateTime::parse_from_rfc3339(&date_updated).map_err(|err| err.to_string())?.with_timezone(&Utc),
+                info: GameInfo {
+                    game_id: Uuid::parse_str(&game_id).map_err(|err| err.to_string())?,
+                    join_code,
+                },
+                state: deserialize_state(schema_version, &mut serde_json::Deserializer::from_str(&state)).map_err(|err| err.to_string())?,
+                finished_at: finished_at
+                    .map(|date| DateTime::parse_from_rfc3339(&date).map(|date| date.with_timezone(&Utc)))
+                    .transpose().map_err(|err| err.to_string())?,
+                outcome: outcome
+                    .map(|outcome| serde_json::from_str(&outcome))
+                    .transpose().map_err(|err| err.to_string())?,
+                variant: variant
+                    .map(|variant| serde_json::from_str(&variant))
+                    .transpose().map_err(|err| err.to_string())?,
+                clocks: clocks
+                    .map(|clocks| serde_json::from_str(&clocks))
+                    .transpose().map_err(|err| err.to_string())?,
+            })
+        };
+        Ok(decode().map_err(|err| StoreError::Serialization(format!("game {}: {}", game_id, err))))
+    }
+}
+
+#[async_trait]
+impl<GameStateType: GameState> GameStore for SqliteStore<GameStateType> {
+    type GameStateT = GameStateType;
+
+    fn new( path: &str ) -> Self {
+        Self::open(path).expect("Failed to open sqlite db")
+    }
+
+    async fn save(&self, record: GameRecord<GameStateType> ) -> Result<(), StoreError> {
+        let player_ids: Vec<Uuid> = record.state.get_players().keys().copied().collect();
+        let player_ids = serde_json::to_string(&player_ids)?;
+        let state = serde_json::to_string(&record.state)?;
+        let outcome = record.outcome.as_ref().map(serde_json::to_string).transpose()?;
+        let variant = record.variant.as_ref().map(serde_json::to_string).transpose()?;
+        let clocks = record.clocks.as_ref().map(serde_json::to_string).transpose()?;
+
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO games (game_id, join_code, date_updated, player_ids, schema_version, state, finished_at, outcome, variant, clocks)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                record.info.game_id.to_string(),
+                record.info.join_code,
+                format_date(&record.date_updated),
+                player_ids,
+                record.schema_version,
+                state,
+                record.finished_at.as_ref().map(format_date),
+                outcome,
+                variant,
+                clocks,
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn delete(&self, game_id: Uuid) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let deleted = connection.execute("DELETE FROM games WHERE game_id = ?1", params![game_id.to_string()])?;
+        if deleted == 0 {
+            return Err(StoreError::NotFound(game_id));
+        }
+        Ok(())
+    }
+
+    async fn load(&self, game_id: Uuid) -> Result<GameRecord<GameStateType>, StoreError> {
+        self.select("WHERE game_id = ?1", params![game_id.to_string()])?
+            .pop()
+            .unwrap_or(Err(StoreError::NotFound(game_id)))
+    }
+
+    async fn find_by_join_code(&self, join_code: &str) -> Result<Option<GameRecord<GameStateType>>, StoreError> {
+        self.select("WHERE join_code = ?1 ORDER BY date_updated DESC LIMIT 1", params![join_code])?
+            .pop()
+            .transpose()
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> Result<Vec<GameRecord<GameStateType>>, StoreError> {
+        self.select_decoded("ORDER BY game_id LIMIT ?1 OFFSET ?2", params![limit as i64, offset as i64])
+    }
+
+    async fn list_updated_before(&self, date: DateTime<Utc>) -> Result<Vec<GameRecord<GameStateType>>, StoreError> {
+        self.select_decoded("WHERE date_updated < ?1", params![format_date(&date)])
+    }
+
+    // The records are read by pages, the connection is not locked between two pages
+    fn iter(&self) -> GameRecordIterator<'_, GameStateType> {
+        let mut page = vec![].into_iter();
+        let mut after = Some(String::new());
+        Box::new(std::iter::from_fn(move || loop {
+            if let Some((_, record)) = page.next() {
+                return Some(record);
+            }
+            let last_id = after.take()?;
+            match self.select_page(&last_id) {
+                Ok(records) => {
+                    // A shorter page is the last one
+                    if records.len() == ITER_PAGE_SIZE {
+                        after = records.last().map(|(game_id, _)| game_id.clone());
+                    }
+                    page = records.into_iter();
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::protocol::test_util::TestState;
+
+    #[tokio::test]
+    async fn iterates_over_several_pages() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = SqliteStore::<TestState>::open(directory.path().join("games.db").to_str().unwrap()).unwrap();
+        let count = ITER_PAGE_SIZE * 2 + 10;
+        for n in 0..count {
+            let info = GameInfo { game_id: Uuid::new_v4(), join_code: format!("{:06}", n) };
+            store.save(GameRecord::create(TestState::default(), info)).await.unwrap();
+        }
+        let game_ids: Vec<Uuid> = store.iter().map(|record| record.unwrap().info.game_id).collect();
+        assert_eq!(game_ids.len(), count);
+        assert_eq!(game_ids.iter().collect::<BTreeSet<_>>().len(), count);
+    }
+}
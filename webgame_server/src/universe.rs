@@ -1,22 +1,28 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::convert::From;
+use std::sync::atomic::{AtomicU64, Ordering};
 
+use chrono::Utc;
 use serde::Serialize;
-use tokio::sync::{mpsc, RwLock};
+use futures::executor::block_on;
+use tokio::sync::{mpsc, Notify, RwLock};
 use uuid::Uuid;
 use warp::ws;
 
+use crate::admin::AdminSettings;
+use crate::archive::ArchiveSink;
+use crate::codec::WireCodec;
 use crate::game::Game;
-use crate::protocol::{Message, PlayerInfo, ProtocolError, ProtocolErrorKind, GameExtendedInfo, GameState, Variant, GameRecord};
+use crate::outbox::Outbox;
+use crate::session::Sessions;
+use crate::protocol::{Message, MessageEnvelope, SessionTokenMessage, GameStateDelta, PlayerInfo, ProtocolError, ProtocolErrorKind, GameExtendedInfo, GameState, Variant, GameRecord, ImportMode};
 use crate::utils::generate_join_code;
-use crate::store::GameStore;
-use crate::store_print::PrintStore;
-use crate::store_sled::SledStore;
+use crate::store::{SharedGameStore, StoreError, run_blocking};
 
 use std::os::unix::net::UnixStream;
 use std::io::Write;
 use std::sync::Mutex;
+use std::mem;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct User {
@@ -54,35 +60,105 @@ impl From<User> for PlayerInfo {
 pub struct UniverseUserState {
     user: User,
     is_authenticated: bool,
+    is_admin: bool,
+    capabilities: HashSet<String>,
+    codec: WireCodec,
+    // Version and content of the last snapshot sent, to compute the next delta
+    last_snapshot: Option<(u64, serde_json::Value)>,
     game_id: Option<Uuid>,
+    // Game watched by the user, who is then not in a game
+    spectating: Option<Uuid>,
     tx: mpsc::UnboundedSender<Result<ws::Message, warp::Error>>,
 }
 
+impl UniverseUserState {
+    // The sequence number is only sent to the clients which enabled the replay capability
+    fn send<M: Serialize>(&self, message: &M, seq: Option<u64>) {
+        let seq = seq.filter(|_| self.capabilities.contains(REPLAY_CAPABILITY));
+        let encoded = match seq {
+            Some(seq) => self.codec.encode(&MessageEnvelope { seq: Some(seq), message }),
+            None => self.codec.encode(message),
+        };
+        match encoded {
+            Ok(msg) => self.send_frame(msg),
+            Err(err) => log::error!("could not encode message for {}: {}", self.user.id, err),
+        }
+    }
+
+    fn send_frame(&self, msg: ws::Message) {
+        if let Err(_disconnected) = self.tx.send(Ok(msg)) {
+            // The tx is disconnected, our `user_disconnected` code
+            // should be happening in another task, nothing more to
+            // do here.
+        }
+    }
+}
+
+// Messages sent to the players of a game
+type GameMessage<GameStateType, PlayEventT> = Message<
+    <GameStateType as GameState>::GamePlayerState,
+    <GameStateType as GameState>::Snapshot,
+    <GameStateType as GameState>::Operation,
+    PlayEventT,
+>;
+
+/// Optional feature : game state changes are sent as `GameStateDelta` messages.
+///
+/// The patches are computed on the JSON form of the snapshots, they are only
+/// sent with the JSON codec.
+pub const DELTA_CAPABILITY: &str = "delta";
+/// Optional feature : messages are numbered, missed ones can be replayed after a reconnection
+pub const REPLAY_CAPABILITY: &str = "replay";
+
 pub struct UniverseState<GameStateType: GameState, PlayEventType> {
         users: HashMap<Uuid, UniverseUserState>,
         games: HashMap<Uuid, Arc<Game<GameStateType, PlayEventType>>>,
         joinable_games: HashMap<String, Uuid>,
+        // Kept while the users are disconnected, until they leave their game
+        outboxes: HashMap<Uuid, Outbox>,
+}
+
+impl<GameStateType: GameState, PlayEventType> UniverseState<GameStateType, PlayEventType> {
+    // Drops the outboxes of the players which are not connected anymore
+    fn drop_outboxes(&mut self, player_ids: impl Iterator<Item=Uuid>) {
+        for player_id in player_ids {
+            if !self.users.contains_key(&player_id) {
+                self.outboxes.remove(&player_id);
+            }
+        }
+    }
 }
 
 pub struct Universe<GameStateType: GameState, PlayEventType> {
         state: Arc<RwLock<UniverseState<GameStateType, PlayEventType>>>,
-        store: Arc<SledStore<GameStateType>>,
+        store: SharedGameStore<GameStateType>,
+        archives: Option<Arc<dyn ArchiveSink<GameStateType>>>,
+        sessions: Sessions,
+        admin: AdminSettings,
+        store_errors: AtomicU64,
+        dirty_games: Mutex<HashSet<Uuid>>,
+        flush_request: Notify,
         str_bots_socket: String,
         // bots_stream: Arc<Mutex<Option<UnixStream>>>,
-        // store: PrintStore<GameStateType>,
 }
 
 impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameStateType, PlayEventT> {
     // pub fn new(db_uri: &str) -> Universe<GameStateType, PlayEventT> {
-    pub fn new(store: Arc<SledStore<GameStateType>>, str_bots_socket: String) -> Universe<GameStateType, PlayEventT> {
+    pub fn new(store: SharedGameStore<GameStateType>, archives: Option<Arc<dyn ArchiveSink<GameStateType>>>, sessions: Sessions, admin: AdminSettings, str_bots_socket: String) -> Universe<GameStateType, PlayEventT> {
         Universe {
             state: Arc::new(RwLock::new(UniverseState {
                 users: HashMap::new(),
                 games: HashMap::new(),
                 joinable_games: HashMap::new(),
+                outboxes: HashMap::new(),
             })),
-            // store: PrintStore::new(&db_uri),
             store,
+            archives,
+            sessions,
+            admin,
+            store_errors: AtomicU64::new(0),
+            dirty_games: Mutex::new(HashSet::new()),
+            flush_request: Notify::new(),
             str_bots_socket,
         }
     }
@@ -120,14 +196,9 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
 
     /// show all stored games
     pub async fn show_stored_games(self: &Arc<Self>) -> Vec<GameRecord<GameStateType>> {
-        // let fgames = self.store.iter()
-        // let fgames: Result<Vec<GameRecord<GameStateType>>, _> = self.store.data().iter()
-        let fgames: Vec<GameRecord<GameStateType>> = self.store.data().iter()
-            .map(|res| res.map(|game| game.1))
-            .filter_map(Result::ok)
-        .collect();
-        fgames
-        // fgames.unwrap()
+        self.store.iter()
+            .filter_map(|record| record.map_err(|err| self.report_store_error("read stored game", &err)).ok())
+            .collect()
     }
 
     /// for debug purposes: show all the users connected to the server, except user_id
@@ -140,6 +211,121 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
         uuids
     }
 
+    /// Registers the join codes of the games kept in the store.
+    ///
+    /// This is called once on server startup so that players can reconnect
+    /// to the games they were playing before a restart. The games themselves
+    /// are only loaded when a player comes back, see `load_game`. Records
+    /// which could not be decoded are reported and left in the store. Returns
+    /// the number of restored games.
+    pub async fn restore_games(self: &Arc<Self>) -> usize {
+        let store = self.store.clone();
+        let listed = tokio::task::spawn_blocking(move || {
+            let mut games = vec![];
+            let mut failed = 0;
+            for record in store.iter() {
+                match record {
+                    Ok(record) => games.push((record.info.join_code, record.info.game_id)),
+                    Err(err) => {
+                        failed += 1;
+                        log::error!("could not restore game: {}", err);
+                    }
+                }
+            }
+            (games, failed)
+        }).await;
+        let (games, failed) = match listed {
+            Ok(listed) => listed,
+            Err(err) => {
+                log::error!("could not list stored games: {}", err);
+                return 0;
+            }
+        };
+        if failed > 0 {
+            log::error!("{} stored games could not be decoded", failed);
+            self.store_errors.fetch_add(failed, Ordering::Relaxed);
+        }
+
+        let mut universe_state = self.state.write().await;
+        let mut restored = 0;
+        for (join_code, game_id) in games {
+            if let Some(other_id) = universe_state.joinable_games.get(&join_code) {
+                log::warn!("join code {} of game {} already used by game {}", join_code, game_id, other_id);
+                continue;
+            }
+            universe_state.joinable_games.insert(join_code, game_id);
+            restored += 1;
+        }
+        restored
+    }
+
+    /// Loads an archived game back into the universe.
+    ///
+    /// See `import_record`.
+    pub async fn import_archive(self: &Arc<Self>, game_id: Uuid, mode: ImportMode) -> Result<Arc<Game<GameStateType, PlayEventT>>, ProtocolError> {
+        let archives = match self.archives {
+            Some(ref archives) => archives.clone(),
+            None => return Err(ProtocolError::new(
+                ProtocolErrorKind::BadState,
+                "no archives configured",
+            )),
+        };
+        let loaded = tokio::task::spawn_blocking(move || archives.load(game_id)).await
+            .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+        match loaded {
+            Ok(Some(record)) => self.import_record(record, mode).await,
+            Ok(None) => Err(ProtocolError::new(
+                ProtocolErrorKind::NotFound,
+                "archive does not exist",
+            )),
+            Err(err) => {
+                log::error!("could not read archive of game {}: {}", game_id, err);
+                Err(ProtocolError::new(
+                    ProtocolErrorKind::InternalError,
+                    format!("could not read archive: {}", err),
+                ))
+            }
+        }
+    }
+
+    /// Adds a game record to the universe under a new join code.
+    ///
+    /// The game keeps its id, so its players can reconnect to it as they did
+    /// before it was archived. A read-only game can only be viewed, a
+    /// resumable one is joinable again and saved in the store.
+    pub async fn import_record(self: &Arc<Self>, mut record: GameRecord<GameStateType>, mode: ImportMode) -> Result<Arc<Game<GameStateType, PlayEventT>>, ProtocolError> {
+        let mut universe_state = self.state.write().await;
+        if universe_state.games.contains_key(&record.info.game_id) {
+            return Err(ProtocolError::new(
+                ProtocolErrorKind::BadState,
+                "game is already loaded",
+            ));
+        }
+
+        let mut join_code = generate_join_code();
+        while universe_state.joinable_games.contains_key(&join_code) {
+            join_code = generate_join_code();
+        }
+        record.info.join_code = join_code;
+        if mode == ImportMode::Resumable {
+            // The game is played again, it is no longer an old one
+            record.date_updated = Utc::now();
+        }
+
+        let game = Arc::new(Game::from_record(record, self.clone(), mode == ImportMode::ReadOnly));
+        universe_state.games.insert(game.id(), game.clone());
+        universe_state
+            .joinable_games
+            .insert(game.join_code().to_string(), game.id());
+        drop(universe_state);
+
+        if mode == ImportMode::Resumable {
+            self.mark_dirty(game.id(), true);
+        }
+        log::info!("imported archived game {} as {} ({:?})", game.id(), game.join_code(), mode);
+        Ok(game)
+    }
+
     /// Starts a new game.
     pub async fn new_game(self: &Arc<Self>, variant: Variant<GameStateType::VariantParameters>) -> Arc<Game<GameStateType, PlayEventT>> {
         let mut universe_state = self.state.write().await;
@@ -162,7 +348,7 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
 
     /// Joins a user into a game by join code.
     pub async fn join_game(
-        &self,
+        self: &Arc<Self>,
         user_id: Uuid,
         join_code: String,
     ) -> Result<Arc<Game<GameStateType, PlayEventT>>, ProtocolError> {
@@ -176,7 +362,7 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
             .copied();
 
         if let Some(game_id) = game_id {
-            if let Some(game) = self.get_game(game_id).await {
+            if let Some(game) = self.load_game(game_id).await {
                 if game.is_joinable().await {
                     game.add_player(user_id).await;
                     return Ok(game);
@@ -195,6 +381,190 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
         ))
     }
 
+    /// Seats a returning player back in a game, by join code.
+    ///
+    /// The player proves its identity with the session token it was given.
+    /// The game is reloaded from the store if it is no longer in memory. The
+    /// connected user takes the id the player had in the game, so the user
+    /// is registered again under `player_id`.
+    pub async fn resume_game(
+        self: &Arc<Self>,
+        user_id: Uuid,
+        join_code: String,
+        player_id: Uuid,
+        token: &str,
+    ) -> Result<(User, Arc<Game<GameStateType, PlayEventT>>), ProtocolError> {
+        if !self.sessions.verify(token, player_id) {
+            return Err(ProtocolError::new(
+                ProtocolErrorKind::NotAuthenticated,
+                "invalid session token",
+            ));
+        }
+        let game_id = self.state.read().await.joinable_games.get(&join_code).copied();
+        let loaded_game = match game_id {
+            Some(game_id) => self.load_game(game_id).await,
+            None => None,
+        };
+        let game = match loaded_game {
+            Some(game) => game,
+            None => self.reload_game(&join_code).await?,
+        };
+        let player = game.get_player(&player_id).await.ok_or_else(|| ProtocolError::new(
+            ProtocolErrorKind::NotFound,
+            "player is not in this game",
+        ))?;
+
+        let mut universe_state = self.state.write().await;
+        if user_id != player_id && universe_state.users.contains_key(&player_id) {
+            return Err(ProtocolError::new(
+                ProtocolErrorKind::BadState,
+                "player is already connected",
+            ));
+        }
+        let mut user_state = universe_state.users.remove(&user_id).ok_or_else(|| ProtocolError::new(
+            ProtocolErrorKind::InternalError,
+            "couldn't find user in state",
+        ))?;
+        user_state.user = player.into();
+        user_state.is_authenticated = true;
+        user_state.game_id = Some(game.id());
+        let user = user_state.user.clone();
+        // Messages numbered for the previous id are not the player's ones
+        universe_state.outboxes.remove(&user_id);
+        if user_state.capabilities.contains(REPLAY_CAPABILITY) {
+            universe_state.outboxes.entry(player_id).or_default();
+        }
+        universe_state.users.insert(player_id, user_state);
+        Ok((user, game))
+    }
+
+    // Loads a stored game back into the universe, by join code
+    async fn reload_game(self: &Arc<Self>, join_code: &str) -> Result<Arc<Game<GameStateType, PlayEventT>>, ProtocolError> {
+        let code = join_code.to_string();
+        let record = run_blocking(&self.store, move |store| block_on(store.find_by_join_code(&code))).await
+            .map_err(|err| {
+                self.report_store_error(&format!("load game {}", join_code), &err);
+                ProtocolError::new(ProtocolErrorKind::InternalError, "could not load the game")
+            })?
+            .ok_or_else(|| ProtocolError::new(
+                ProtocolErrorKind::NotFound,
+                "game does not exist",
+            ))?;
+
+        let mut universe_state = self.state.write().await;
+        // The game may have been reloaded meanwhile
+        if let Some(game) = universe_state.games.get(&record.info.game_id) {
+            return Ok(game.clone());
+        }
+        let code_in_use = universe_state.joinable_games.get(join_code)
+            .is_some_and(|other_id| universe_state.games.contains_key(other_id));
+        if code_in_use {
+            return Err(ProtocolError::new(
+                ProtocolErrorKind::BadState,
+                "join code is used by another game",
+            ));
+        }
+        let game = Arc::new(Game::from_record(record, self.clone(), false));
+        universe_state.games.insert(game.id(), game.clone());
+        universe_state
+            .joinable_games
+            .insert(game.join_code().to_string(), game.id());
+        log::debug!("reloaded game {}", game.id());
+        Ok(game)
+    }
+
+    /// Returns a game by ID, reloading it from the store if it was evicted.
+    pub async fn load_game(self: &Arc<Self>, game_id: Uuid) -> Option<Arc<Game<GameStateType, PlayEventT>>> {
+        if let Some(game) = self.get_game(game_id).await {
+            return Some(game);
+        }
+        let record = match run_blocking(&self.store, move |store| block_on(store.load(game_id))).await {
+            Ok(record) => record,
+            Err(StoreError::NotFound(_)) => return None,
+            Err(err) => {
+                self.report_store_error(&format!("load game {}", game_id), &err);
+                return None;
+            }
+        };
+
+        let mut universe_state = self.state.write().await;
+        // The game may have been reloaded meanwhile
+        if let Some(game) = universe_state.games.get(&game_id) {
+            return Some(game.clone());
+        }
+        let game = Arc::new(Game::from_record(record, self.clone(), false));
+        match universe_state.joinable_games.get(game.join_code()) {
+            Some(other_id) if *other_id != game_id => {
+                log::warn!("join code {} of game {} already used by game {}", game.join_code(), game_id, other_id);
+            }
+            _ => {
+                universe_state
+                    .joinable_games
+                    .insert(game.join_code().to_string(), game_id);
+            }
+        }
+        universe_state.games.insert(game_id, game.clone());
+        log::debug!("reloaded game {}", game_id);
+        Some(game)
+    }
+
+    /// Saves and drops the games without activity for `idle_after`.
+    ///
+    /// Only the games modified since their last save are written. Their join
+    /// codes stay reserved, so that they are reloaded from the store when
+    /// players come back. Their clocks are saved too : a player who ran out
+    /// of time meanwhile times out once the game is reloaded. Read-only games
+    /// are not in the store, they are simply dropped. Returns the number of
+    /// evicted games.
+    pub async fn evict_idle_games(&self, idle_after: std::time::Duration) -> usize {
+        let idle_games: Vec<_> = self.state.read().await.games.values()
+            // Games being watched are kept
+            .filter(|game| game.last_activity().elapsed() >= idle_after && game.spectators().is_empty())
+            .cloned()
+            .collect();
+
+        let mut evicted = 0;
+        for game in idle_games {
+            let last_activity = game.last_activity();
+            // Taken from the dirty set so that the write-behind task does not save it too
+            let dirty = self.dirty_games.lock().unwrap().remove(&game.id());
+            if dirty && self.store_state(&game).await.is_err() {
+                self.dirty_games.lock().unwrap().insert(game.id());
+                continue;
+            }
+            let mut universe_state = self.state.write().await;
+            // Games which were used while being saved are kept
+            if game.last_activity() != last_activity || self.dirty_games.lock().unwrap().contains(&game.id()) {
+                continue;
+            }
+            universe_state.games.remove(&game.id());
+            if game.is_read_only() && universe_state.joinable_games.get(game.join_code()) == Some(&game.id()) {
+                universe_state.joinable_games.remove(game.join_code());
+            }
+            drop(universe_state);
+            self.drop_game_outboxes(&game).await;
+            log::debug!("evicted idle game {}", game.id());
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Frees the join code of a stored game which is no longer in the store.
+    pub async fn release_join_code(&self, join_code: &str, game_id: Uuid) {
+        let mut universe_state = self.state.write().await;
+        if universe_state.joinable_games.get(join_code) == Some(&game_id) && !universe_state.games.contains_key(&game_id) {
+            universe_state.joinable_games.remove(join_code);
+        }
+    }
+
+    /// Returns the games with a time control.
+    pub async fn timed_games(&self) -> Vec<Arc<Game<GameStateType, PlayEventT>>> {
+        self.state.read().await.games.values()
+            .filter(|game| game.has_clocks())
+            .cloned()
+            .collect()
+    }
+
     pub async fn debug_game(
         &self,
         game_id: Uuid,
@@ -214,12 +584,14 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
     /// Registers a user.
     ///
     /// The user is given a new ID which is returned and starts out without
-    /// any associated nickname.
+    /// any associated nickname. A player reconnecting to its game keeps its
+    /// ID if it gives a valid session token.
     pub async fn add_user(
-        &self,
+        self: &Arc<Self>,
         tx: mpsc::UnboundedSender<Result<ws::Message, warp::Error>>,
         guid: String,
         uuid: String,
+        token: Option<String>,
     ) -> (User, Option<Uuid>) {
         //Defaults for a new user
         let mut user_id = Uuid::new_v4();
@@ -229,8 +601,12 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
 
         // Check validity of given uuid
         if let (Ok(user_uuid),  Ok(game_uid)) = (Uuid::parse_str(&uuid), Uuid::parse_str(&guid)) {
-            //Check if user is in a active game
-            if let Some(user) = self.find_user_game(game_uid, user_uuid).await {
+            // The player must prove it owns this uuid
+            let has_session = token.as_deref().is_some_and(|token| self.sessions.verify(token, user_uuid));
+            if !has_session {
+                log::warn!("reconnection of {} refused : invalid session token", user_uuid);
+            } else if let Some(user) = self.find_user_game(game_uid, user_uuid).await {
+                //User is in a active game
                 user_id = user_uuid;
                 game_id = Some(game_uid);
                 is_authenticated = true; 
@@ -250,6 +626,11 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
                 user: user.clone(),
                 game_id,
                 is_authenticated,
+                is_admin: false,
+                capabilities: HashSet::new(),
+                codec: WireCodec::default(),
+                last_snapshot: None,
+                spectating: None,
                 tx,
             },
         );
@@ -303,10 +684,86 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
         }
     }
 
+    /// Returns who can use the administration commands.
+    pub fn admin_settings(&self) -> &AdminSettings {
+        &self.admin
+    }
+
+    /// Gives the admin role to the connection of a user.
+    pub async fn set_user_admin(&self, user_id: Uuid) {
+        let mut universe_state = self.state.write().await;
+        if let Some(state) = universe_state.users.get_mut(&user_id) {
+            state.is_admin = true;
+        }
+    }
+
+    /// Checks if the connection of a user has the admin role.
+    pub async fn user_is_admin(&self, user_id: Uuid) -> bool {
+        let universe_state = self.state.read().await;
+        universe_state.users.get(&user_id).is_some_and(|state| state.is_admin)
+    }
+
+    /// Sets the optional features negotiated with the client of a user.
+    pub async fn set_user_capabilities(&self, user_id: Uuid, capabilities: HashSet<String>) {
+        let mut universe_state = self.state.write().await;
+        if capabilities.contains(REPLAY_CAPABILITY) {
+            universe_state.outboxes.entry(user_id).or_default();
+        }
+        if let Some(state) = universe_state.users.get_mut(&user_id) {
+            state.capabilities = capabilities;
+        }
+    }
+
+    /// Checks if an optional feature was negotiated with the client of a user.
+    pub async fn user_has_capability(&self, user_id: Uuid, capability: &str) -> bool {
+        let universe_state = self.state.read().await;
+        universe_state.users.get(&user_id)
+            .is_some_and(|state| state.capabilities.contains(capability))
+    }
+
+    /// Sets the encoding of the messages sent to a user.
+    pub async fn set_user_codec(&self, user_id: Uuid, codec: WireCodec) {
+        let mut universe_state = self.state.write().await;
+        if let Some(state) = universe_state.users.get_mut(&user_id) {
+            state.codec = codec;
+        }
+    }
+
+    /// Returns the encoding negotiated with the client of a user.
+    pub async fn user_codec(&self, user_id: Uuid) -> WireCodec {
+        let universe_state = self.state.read().await;
+        universe_state.users.get(&user_id)
+            .map(|state| state.codec)
+            .unwrap_or_default()
+    }
+
+    /// Issues a new session token to a user.
+    pub fn issue_session(&self, user_id: Uuid) -> SessionTokenMessage {
+        let (token, expires_at) = self.sessions.issue(user_id);
+        SessionTokenMessage { token, expires_at }
+    }
+
+    /// Revokes the session tokens issued to a user.
+    pub fn revoke_sessions(&self, user_id: Uuid) {
+        self.sessions.revoke(user_id);
+    }
+
     /// Unregisters a user.
+    ///
+    /// The messages sent to the user are kept while it is still in a game,
+    /// until it reconnects.
     pub async fn remove_user(&self, user_id: Uuid) {
         let mut universe_state = self.state.write().await;
-        universe_state.users.remove(&user_id);
+        let user_state = universe_state.users.remove(&user_id);
+        let in_game = user_state.as_ref().is_some_and(|state| state.game_id.is_some());
+        if !in_game {
+            universe_state.outboxes.remove(&user_id);
+        }
+        let spectated_game = user_state.and_then(|state| state.spectating)
+            .and_then(|game_id| universe_state.games.get(&game_id));
+        if let Some(game) = spectated_game {
+            game.remove_spectator(user_id);
+        }
     }
 
     /// Sets the current game of a user.
@@ -326,35 +783,107 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
         universe_state.games.get(&game_id).cloned()
     }
 
-    /// Removes a game from the universe.
+    /// Removes a game from the universe, its join code can then be reused.
+    ///
+    /// Pending changes of the game are saved before it is dropped.
     pub async fn remove_game(&self, game_id: Uuid) -> bool {
         let mut universe_state = self.state.write().await;
-        universe_state.games.remove(&game_id).is_some()
+        let game = universe_state.games.remove(&game_id);
+        let mut spectator_ids = vec![];
+        if let Some(ref game) = game {
+            if universe_state.joinable_games.get(game.join_code()) == Some(&game_id) {
+                universe_state.joinable_games.remove(game.join_code());
+            }
+            for spectator_id in game.spectators() {
+                if let Some(state) = universe_state.users.get_mut(&spectator_id) {
+                    state.spectating = None;
+                    spectator_ids.push(spectator_id);
+                }
+            }
+        }
+        drop(universe_state);
+        if let Some(ref game) = game {
+            if self.dirty_games.lock().unwrap().remove(&game_id) {
+                let _ = self.store_state(game).await;
+            }
+            self.drop_game_outboxes(game).await;
+        }
+        // There is nothing left to watch
+        for spectator_id in spectator_ids {
+            self.send(spectator_id, &Message::GameLeft).await;
+        }
+        game.is_some()
     }
 
-    /// Returns the game a user is in.
-    pub async fn get_user_game(&self, user_id: Uuid) -> Option<Arc<Game<GameStateType, PlayEventT>>> {
-        let universe_state = self.state.read().await;
-        universe_state
+    // The game state is locked before the universe state, as in `Game::broadcast`
+    async fn drop_game_outboxes(&self, game: &Game<GameStateType, PlayEventT>) {
+        let player_ids: Vec<Uuid> = game.state_handle().lock().await.get_players().keys().copied().collect();
+        self.state.write().await.drop_outboxes(player_ids.into_iter());
+    }
+
+    /// Returns the game a user is in, reloading it if it was evicted.
+    pub async fn get_user_game(self: &Arc<Self>, user_id: Uuid) -> Option<Arc<Game<GameStateType, PlayEventT>>> {
+        let game_id = self.state.read().await
             .users
             .get(&user_id)
-            .and_then(|user| user.game_id)
-            .and_then(|game_id| universe_state.games.get(&game_id))
-            .cloned()
+            .and_then(|user| user.game_id)?;
+        self.load_game(game_id).await
     }
 
-    /// Find a game with the user
-    pub async fn find_user_game(&self, game_id: Uuid, user_id: Uuid) -> Option<User> {
+    /// Makes a user watch a game by join code, without taking a seat.
+    ///
+    /// Games which are not joinable anymore or read-only can be watched too.
+    pub async fn spectate_game(
+        self: &Arc<Self>,
+        user_id: Uuid,
+        join_code: String,
+    ) -> Result<Arc<Game<GameStateType, PlayEventT>>, ProtocolError> {
+        let game_id = self.state.read().await.joinable_games.get(&join_code).copied();
+        let loaded_game = match game_id {
+            Some(game_id) => self.load_game(game_id).await,
+            None => None,
+        };
+        let game = loaded_game.ok_or_else(|| ProtocolError::new(
+            ProtocolErrorKind::NotFound,
+            "game does not exist",
+        ))?;
+
+        self.stop_spectating(user_id).await;
+        let mut universe_state = self.state.write().await;
+        let user_state = universe_state.users.get_mut(&user_id).ok_or_else(|| ProtocolError::new(
+            ProtocolErrorKind::InternalError,
+            "couldn't find user in state",
+        ))?;
+        user_state.spectating = Some(game.id());
+        game.add_spectator(user_id);
+        Ok(game)
+    }
+
+    /// Returns the game watched by a user.
+    pub async fn get_spectated_game(&self, user_id: Uuid) -> Option<Arc<Game<GameStateType, PlayEventT>>> {
         let universe_state = self.state.read().await;
-        let mut player = None;
-        if let Some(game) = universe_state.games.get(&game_id) {
-            player = game.get_player(&user_id).await;
+        let game_id = universe_state.users.get(&user_id).and_then(|user| user.spectating)?;
+        universe_state.games.get(&game_id).cloned()
+    }
+
+    /// Stops watching a game, returns false if the user was not a spectator.
+    pub async fn stop_spectating(&self, user_id: Uuid) -> bool {
+        let mut universe_state = self.state.write().await;
+        let game_id = universe_state.users.get_mut(&user_id).and_then(|user| user.spectating.take());
+        if let Some(game) = game_id.and_then(|game_id| universe_state.games.get(&game_id)) {
+            game.remove_spectator(user_id);
         }
-        player.map(|pl| pl.into())
+        game_id.is_some()
+    }
+
+    /// Find a game with the user
+    pub async fn find_user_game(self: &Arc<Self>, game_id: Uuid, user_id: Uuid) -> Option<User> {
+        let game = self.load_game(game_id).await?;
+        game.get_player(&user_id).await.map(|pl| pl.into())
     }
 
     /// Makes the user leave the game they are in.
-    pub async fn remove_user_from_game(&self, user_id: Uuid) {
+    pub async fn remove_user_from_game(self: &Arc<Self>, user_id: Uuid) {
         if let Some(game) = self.get_user_game(user_id).await {
             game.remove_user(user_id).await;
         }
@@ -362,18 +891,365 @@ impl<GameStateType: Default+GameState, PlayEventT:Serialize+Send> Universe<GameS
 
     /// Send a message to a single user.
     pub async fn send(&self, user_id: Uuid, message: &Message<GameStateType::GamePlayerState, GameStateType::Snapshot, GameStateType::Operation, PlayEventT>) {
-        let universe_state = self.state.write().await;
-        if let Some(ref state) = universe_state.users.get(&user_id) {
-            let s = serde_json::to_string(message).unwrap();
-            if let Err(_disconnected) = state.tx.send(Ok(ws::Message::text(s))) {
-                // The tx is disconnected, our `user_disconnected` code
-                // should be happening in another task, nothing more to
-                // do here.
+        let mut universe_state = self.state.write().await;
+        let UniverseState { users, outboxes, .. } = &mut *universe_state;
+        // Play events, chat and the end of the game are kept for the disconnected users too
+        let replayable = matches!(message, Message::PlayEvent(_) | Message::Chat(_) | Message::SpectatorChat(_) | Message::GameFinished(_));
+        let seq = outboxes.get_mut(&user_id).map(|outbox| outbox.record(message, replayable));
+        if let Some(state) = users.get_mut(&user_id) {
+            // The next delta can not be based on a snapshot sent outside of `send_snapshot`
+            if let Message::GameStateSnapshot(_) = message {
+                state.last_snapshot = None;
+            }
+            state.send(message, seq);
+        }
+    }
+
+    /// Sends again to a user the kept messages following `last_seq`.
+    ///
+    /// Returns the number of messages sent.
+    pub async fn replay(&self, user_id: Uuid, last_seq: u64) -> usize {
+        let universe_state = self.state.read().await;
+        let (state, outbox) = match (universe_state.users.get(&user_id), universe_state.outboxes.get(&user_id)) {
+            (Some(state), Some(outbox)) => (state, outbox),
+            _ => return 0,
+        };
+        let mut replayed = 0;
+        for (_, frame) in outbox.since(last_seq, state.codec) {
+            state.send_frame(frame.clone());
+            replayed += 1;
+        }
+        replayed
+    }
+
+    /// Sends a game state snapshot to a single user.
+    ///
+    /// When the client enabled the `delta` capability and uses the JSON
+    /// codec, only the changes since the last snapshot it received are sent,
+    /// unless the full snapshot is smaller.
+    pub async fn send_snapshot(&self, user_id: Uuid, snapshot: GameStateType::Snapshot) {
+        let mut universe_state = self.state.write().await;
+        let UniverseState { users, outboxes, .. } = &mut *universe_state;
+        let state = match users.get_mut(&user_id) {
+            Some(state) => state,
+            None => return,
+        };
+        let message = if state.capabilities.contains(DELTA_CAPABILITY) && state.codec == WireCodec::Json {
+            match Self::make_delta(state, &snapshot) {
+                Some(delta) => GameMessage::<GameStateType, PlayEventT>::GameStateDelta(delta),
+                None => GameMessage::<GameStateType, PlayEventT>::GameStateSnapshot(snapshot),
+            }
+        } else {
+            GameMessage::<GameStateType, PlayEventT>::GameStateSnapshot(snapshot)
+        };
+        let seq = outboxes.get_mut(&user_id).map(|outbox| outbox.record(&message, false));
+        state.send(&message, seq);
+    }
+
+    // Returns the changes since the last snapshot sent to the user, if they
+    // are smaller than the full snapshot, and remembers the new snapshot
+    fn make_delta(state: &mut UniverseUserState, snapshot: &GameStateType::Snapshot) -> Option<GameStateDelta> {
+        let value = match serde_json::to_value(snapshot) {
+            Ok(value) => value,
+            Err(err) => {
+                log::error!("could not encode snapshot for {}: {}", state.user.id, err);
+                state.last_snapshot = None;
+                return None;
+            }
+        };
+
+        let delta = state.last_snapshot.take().and_then(|(version, last_value)| {
+            let patch = json_patch::diff(&last_value, &value);
+            let patch_len = serde_json::to_vec(&patch).map(|bytes| bytes.len()).unwrap_or(usize::MAX);
+            let snapshot_len = serde_json::to_vec(&value).map(|bytes| bytes.len()).unwrap_or(0);
+            Some(GameStateDelta { base_version: version, version: version + 1, patch })
+                .filter(|_| patch_len < snapshot_len)
+        });
+        let version = delta.as_ref().map(|delta| delta.version).unwrap_or(0);
+        state.last_snapshot = Some((version, value));
+        delta
+    }
+
+    /// Forgets the last snapshot sent to a user, the next one is sent in full.
+    pub async fn reset_snapshot(&self, user_id: Uuid) {
+        let mut universe_state = self.state.write().await;
+        if let Some(state) = universe_state.users.get_mut(&user_id) {
+            state.last_snapshot = None;
+        }
+    }
+
+    /// Marks a game as modified since it was last saved.
+    ///
+    /// Modified games are saved by the persistence task, either on its next
+    /// periodic flush or right away when `urgent` is set.
+    pub fn mark_dirty(&self, game_id: Uuid, urgent: bool) {
+        self.dirty_games.lock().unwrap().insert(game_id);
+        if urgent {
+            self.flush_request.notify_one();
+        }
+    }
+
+    /// Waits until a game is marked dirty with `urgent` set.
+    pub async fn flush_requested(&self) {
+        self.flush_request.notified().await
+    }
+
+    /// Saves all the games modified since the last flush.
+    ///
+    /// Games which could not be saved stay dirty and are tried again on the
+    /// next flush. Returns the number of games saved.
+    pub async fn flush_dirty_games(&self) -> usize {
+        let game_ids = mem::take(&mut *self.dirty_games.lock().unwrap());
+        let mut saved = 0;
+        for game_id in game_ids {
+            if let Some(game) = self.get_game(game_id).await {
+                if self.store_state(&game).await.is_ok() {
+                    saved += 1;
+                } else {
+                    self.dirty_games.lock().unwrap().insert(game_id);
+                }
             }
         }
+        saved
+    }
+
+    /// Saves the game in the store.
+    ///
+    /// The store is accessed from a blocking thread, see `run_blocking`. Failures are logged and counted, see `store_errors`.
+    pub async fn store_state(&self, game: &Game<GameStateType, PlayEventT>) -> Result<(), StoreError> {
+        let record = game.make_record().await;
+        let res = run_blocking(&self.store, move |store| block_on(store.save(record))).await;
+        if let Err(ref err) = res {
+            self.report_store_error(&format!("store game {}", game.id()), err);
+        }
+        res
+    }
+
+    pub fn store(&self) -> &SharedGameStore<GameStateType> {
+        &self.store
+    }
+
+    /// Logs a failed store operation and counts it in `store_errors`.
+    pub fn report_store_error(&self, action: &str, err: &StoreError) {
+        self.store_errors.fetch_add(1, Ordering::Relaxed);
+        log::error!("could not {}: {}", action, err);
+    }
+
+    /// Number of failed store operations since the server started.
+    ///
+    /// The records a backend skips while listing the games, see `skip_errors`,
+    /// are only logged.
+    pub fn store_errors(&self) -> u64 {
+        self.store_errors.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ChatMessage, TimeControl};
+    use crate::store::{GameStore, MemoryStore};
+    use crate::protocol::test_util::{TestSnapshot, TestState};
+
+    type Frames = mpsc::UnboundedReceiver<Result<ws::Message, warp::Error>>;
+
+    fn make_universe(store: Arc<MemoryStore<TestState>>) -> Arc<Universe<TestState, ()>> {
+        let sessions = Sessions::with_random_secret(chrono::Duration::hours(1));
+        Arc::new(Universe::new(store, None, sessions, AdminSettings::default(), String::new()))
+    }
+
+    // Registers a new user, the messages sent to it can be read from the returned receiver
+    async fn connect(universe: &Arc<Universe<TestState, ()>>) -> (Uuid, Frames) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (user, _) = universe.add_user(tx, String::new(), String::new(), None).await;
+        (user.id, rx)
+    }
+
+    #[tokio::test]
+    async fn flush_saves_modified_games_only() {
+        let store = Arc::new(MemoryStore::new("memory://"));
+        let universe = make_universe(store.clone());
+        let game = universe.new_game(Variant { parameters: 0, time_control: Default::default() }).await;
+
+        game.broadcast(&Message::PlayEvent(())).await;
+        assert_eq!(universe.flush_dirty_games().await, 1);
+        assert_eq!(store.save_count(game.id()), 1);
+
+        // Chat messages do not change the game
+        game.broadcast(&Message::Chat(ChatMessage { player_id: Uuid::new_v4(), text: "hello".into() })).await;
+        assert_eq!(universe.flush_dirty_games().await, 0);
+        assert_eq!(store.save_count(game.id()), 1);
+
+        // Changes are coalesced until the next flush
+        game.broadcast(&Message::PlayEvent(())).await;
+        game.broadcast(&Message::PlayEvent(())).await;
+        assert_eq!(universe.flush_dirty_games().await, 1);
+        assert_eq!(store.save_count(game.id()), 2);
     }
 
-    pub async fn store_state(&self, game: &Game<GameStateType, PlayEventT>) -> bool {
-        self.store.save(game).await
+    #[tokio::test]
+    async fn keeps_the_outcome_once_the_game_is_finished() {
+        let store = Arc::new(MemoryStore::new("memory://"));
+        let universe = make_universe(store.clone());
+        let game = universe.new_game(Variant { parameters: 0, time_control: Default::default() }).await;
+        let (first, _first_frames) = connect(&universe).await;
+        let (second, _second_frames) = connect(&universe).await;
+        game.add_player(first).await;
+        game.add_player(second).await;
+
+        game.state_handle().lock().await.finished = true;
+        game.broadcast_current_state().await;
+        game.remove_user(second).await;
+        universe.flush_dirty_games().await;
+
+        let record = store.load(game.id()).await.unwrap();
+        assert!(record.is_finished());
+        assert_eq!(record.outcome.unwrap().players.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn evicted_timed_game_times_out_once_reloaded() {
+        let store = Arc::new(MemoryStore::new("memory://"));
+        let universe = make_universe(store.clone());
+        let time_control = TimeControl { per_turn: Some(60), per_game: None };
+        let game = universe.new_game(Variant { parameters: 0, time_control }).await;
+        let game_id = game.id();
+        let (player, _frames) = connect(&universe).await;
+        game.add_player(player).await;
+        game.broadcast_current_state().await;
+        universe.flush_dirty_games().await;
+        assert_eq!(universe.evict_idle_games(std::time::Duration::ZERO).await, 1);
+        drop(game);
+
+        // The game stays evicted longer than the turn time
+        let mut record = store.load(game_id).await.unwrap();
+        let turn = record.clocks.as_mut().and_then(|clocks| clocks.turn.as_mut()).unwrap();
+        assert_eq!(turn.player_id, player);
+        turn.started_at = turn.started_at - chrono::Duration::minutes(2);
+        store.save(record).await.unwrap();
+
+        let game = universe.load_game(game_id).await.unwrap();
+        game.check_timeout().await;
+        assert_eq!(game.state_handle().lock().await.timed_out, vec![player]);
+    }
+
+    #[tokio::test]
+    async fn resumes_evicted_games_from_the_store() {
+        let store = Arc::new(MemoryStore::new("memory://"));
+        let sessions = || Sessions::new(b"secret", chrono::Duration::hours(1));
+        let universe = Arc::new(Universe::new(store.clone(), None, sessions(), AdminSettings::default(), String::new()));
+        let game = universe.new_game(Variant { parameters: 0, time_control: Default::default() }).await;
+        let (game_id, join_code) = (game.id(), game.join_code().to_string());
+        let (player, _frames) = connect(&universe).await;
+        game.add_player(player).await;
+        drop(game);
+        let (token, _) = universe.sessions.issue(player);
+        universe.remove_user(player).await;
+        assert_eq!(universe.evict_idle_games(std::time::Duration::ZERO).await, 1);
+        assert!(universe.get_game(game_id).await.is_none());
+
+        let (user_id, _frames) = connect(&universe).await;
+        let (user, game) = universe.resume_game(user_id, join_code.clone(), player, &token).await.unwrap();
+        assert_eq!((user.id, game.id()), (player, game_id));
+        assert_eq!(universe.get_user_game(player).await.map(|game| game.id()), Some(game_id));
+
+        // After a restart, the join code is only known from the store
+        universe.remove_user(player).await;
+        universe.evict_idle_games(std::time::Duration::ZERO).await;
+        let restarted = Arc::new(Universe::new(store, None, sessions(), AdminSettings::default(), String::new()));
+        let (user_id, _frames) = connect(&restarted).await;
+        let (user, game) = restarted.resume_game(user_id, join_code, player, &token).await.unwrap();
+        assert_eq!((user.id, game.id()), (player, game_id));
+        assert!(game.get_player(&player).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn counts_the_store_errors() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(directory.path().join("broken.json"), "{").unwrap();
+        let store = crate::store::open_store(&format!("json://{}", directory.path().to_str().unwrap())).unwrap();
+        let sessions = Sessions::with_random_secret(chrono::Duration::hours(1));
+        let universe = Arc::new(Universe::<TestState, ()>::new(store, None, sessions, AdminSettings::default(), String::new()));
+
+        assert_eq!(universe.restore_games().await, 0);
+        assert_eq!(universe.store_errors(), 1);
+        assert!(universe.show_stored_games().await.is_empty());
+        assert_eq!(universe.store_errors(), 2);
+        // A missing game is not an error
+        assert!(universe.load_game(Uuid::new_v4()).await.is_none());
+        assert_eq!(universe.store_errors(), 2);
+    }
+
+    type TestMessage = GameMessage<TestState, ()>;
+
+    fn next_message(frames: &mut Frames, codec: WireCodec) -> TestMessage {
+        codec.decode(&frames.try_recv().unwrap().unwrap()).unwrap()
+    }
+
+    fn snapshot(score: u32) -> TestSnapshot {
+        let players = (1..=2).map(|id| PlayerInfo { id: Uuid::from_u128(id), nickname: format!("player {}", id) }).collect();
+        TestSnapshot { score, players }
+    }
+
+    #[tokio::test]
+    async fn deltas_apply_to_the_last_snapshot_received() {
+        let universe = make_universe(Arc::new(MemoryStore::new("memory://")));
+        let (user_id, mut frames) = connect(&universe).await;
+        universe.set_user_capabilities(user_id, vec![DELTA_CAPABILITY.to_string()].into_iter().collect()).await;
+
+        universe.send_snapshot(user_id, snapshot(1)).await;
+        universe.send_snapshot(user_id, snapshot(2)).await;
+        let previous = match next_message(&mut frames, WireCodec::Json) {
+            Message::GameStateSnapshot(previous) => previous,
+            message => panic!("unexpected message {:?}", message),
+        };
+        let delta = match next_message(&mut frames, WireCodec::Json) {
+            Message::GameStateDelta(delta) => delta,
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert_eq!((delta.base_version, delta.version), (0, 1));
+        let mut value = serde_json::to_value(previous).unwrap();
+        json_patch::patch(&mut value, &delta.patch).unwrap();
+        assert_eq!(serde_json::from_value::<TestSnapshot>(value).unwrap(), snapshot(2));
+    }
+
+    #[tokio::test]
+    async fn binary_codecs_get_full_snapshots() {
+        let universe = make_universe(Arc::new(MemoryStore::new("memory://")));
+        let (user_id, mut frames) = connect(&universe).await;
+        universe.set_user_capabilities(user_id, vec![DELTA_CAPABILITY.to_string()].into_iter().collect()).await;
+        universe.set_user_codec(user_id, WireCodec::MessagePack).await;
+
+        universe.send_snapshot(user_id, snapshot(1)).await;
+        universe.send_snapshot(user_id, snapshot(2)).await;
+        for expected in [snapshot(1), snapshot(2)] {
+            match next_message(&mut frames, WireCodec::MessagePack) {
+                Message::GameStateSnapshot(snapshot) => assert_eq!(snapshot, expected),
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_the_frames_first_sent() {
+        let universe = make_universe(Arc::new(MemoryStore::new("memory://")));
+        let (user_id, mut frames) = connect(&universe).await;
+        universe.set_user_capabilities(user_id, vec![REPLAY_CAPABILITY.to_string()].into_iter().collect()).await;
+        universe.set_user_codec(user_id, WireCodec::MessagePack).await;
+
+        let chat = ChatMessage { player_id: user_id, text: "hello".into() };
+        universe.send(user_id, &Message::Chat(chat)).await;
+        let sent = frames.try_recv().unwrap().unwrap();
+        assert!(sent.is_binary());
+
+        assert_eq!(universe.replay(user_id, 0).await, 1);
+        let replayed = frames.try_recv().unwrap().unwrap();
+        assert_eq!(replayed.as_bytes(), sent.as_bytes());
+        let envelope: MessageEnvelope<TestMessage> = WireCodec::MessagePack.decode(&replayed).unwrap();
+        assert_eq!(envelope.seq, Some(1));
+        match envelope.message {
+            Message::Chat(chat) => assert_eq!(chat.text, "hello"),
+            message => panic!("unexpected message {:?}", message),
+        }
     }
 }
@@ -1,3 +1,7 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
+
 use rand::{seq::SliceRandom, thread_rng};
 
 const CHARS: &[u8; 22] = b"BCDFGHJKLMNPQRSTUVWXZY";
@@ -8,3 +12,20 @@ pub fn generate_join_code() -> String {
         .map(|_| *CHARS.choose(&mut rng).unwrap() as char)
         .collect()
 }
+
+/// Writes a file through a temporary file renamed once complete, so that a
+/// crash never leaves a partially written file.
+///
+/// The temporary file is hidden : its name starts with a dot.
+pub fn write_atomically(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<()> {
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    write(&mut writer)?;
+    writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(&tmp_path, path)
+}